@@ -0,0 +1 @@
+ALTER TABLE identities ADD COLUMN expires DATETIME;
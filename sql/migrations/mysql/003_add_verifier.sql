@@ -0,0 +1,4 @@
+-- Hashed tokens (base64 SHA-256) no longer fit in CHAR(32)
+ALTER TABLE identities
+	MODIFY token VARCHAR(64) NOT NULL,
+	ADD COLUMN verifier TEXT;
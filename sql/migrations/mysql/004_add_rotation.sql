@@ -0,0 +1,4 @@
+ALTER TABLE identities
+	ADD COLUMN previous TEXT,
+	ADD COLUMN rotated DATETIME,
+	ADD INDEX identities_previous (previous(64));
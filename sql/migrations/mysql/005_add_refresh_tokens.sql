@@ -0,0 +1,5 @@
+ALTER TABLE identities
+	ADD COLUMN kind VARCHAR(16) NOT NULL DEFAULT 'access',
+	ADD COLUMN family TEXT,
+	ADD COLUMN revoked DATETIME,
+	ADD INDEX identities_family (family(64));
@@ -0,0 +1,3 @@
+ALTER TABLE identities ADD COLUMN previous TEXT;
+ALTER TABLE identities ADD COLUMN rotated timestamp;
+CREATE INDEX identities_previous ON identities (previous);
@@ -0,0 +1,4 @@
+ALTER TABLE identities ADD COLUMN kind TEXT NOT NULL DEFAULT 'access';
+ALTER TABLE identities ADD COLUMN family TEXT;
+ALTER TABLE identities ADD COLUMN revoked timestamp;
+CREATE INDEX identities_family ON identities (family);
@@ -1,13 +1,18 @@
-CREATE DATABASE twinscroll;
-
-USE twinscroll;
-
 CREATE TABLE identities (
 	id BIGINT PRIMARY KEY AUTO_INCREMENT NOT NULL,
-	token CHAR(32) UNIQUE NOT NULL,
+	token VARCHAR(64) UNIQUE NOT NULL,
 	userid TEXT NOT NULL,
 	ip TEXT,
 	useragent TEXT,
 	created DATETIME NOT NULL,
-	modified DATETIME NOT NULL
+	modified DATETIME NOT NULL,
+	expires DATETIME,
+	verifier TEXT,
+	previous TEXT,
+	rotated DATETIME,
+	kind VARCHAR(16) NOT NULL DEFAULT 'access',
+	family TEXT,
+	revoked DATETIME
 );
+CREATE INDEX identities_previous ON identities (previous(64));
+CREATE INDEX identities_family ON identities (family(64));
@@ -1,7 +1,3 @@
-CREATE DATABASE testdb;
-
-\c testdb
-
 CREATE TABLE identities (
 	id BIGSERIAL PRIMARY KEY NOT NULL,
 	token TEXT UNIQUE NOT NULL,
@@ -9,5 +5,14 @@ CREATE TABLE identities (
 	ip TEXT,
 	useragent TEXT,
 	created timestamp NOT NULL,
-	modified timestamp NOT NULL
+	modified timestamp NOT NULL,
+	expires timestamp,
+	verifier TEXT,
+	previous TEXT,
+	rotated timestamp,
+	kind TEXT NOT NULL DEFAULT 'access',
+	family TEXT,
+	revoked timestamp
 );
+CREATE INDEX identities_previous ON identities (previous);
+CREATE INDEX identities_family ON identities (family);
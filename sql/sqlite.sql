@@ -5,5 +5,14 @@ CREATE TABLE identities (
 	ip TEXT,
 	useragent TEXT,
 	created DATETIME NOT NULL,
-	modified DATETIME NOT NULL
+	modified DATETIME NOT NULL,
+	expires DATETIME,
+	verifier TEXT,
+	previous TEXT,
+	rotated DATETIME,
+	kind TEXT NOT NULL DEFAULT 'access',
+	family TEXT,
+	revoked DATETIME
 );
+CREATE INDEX identities_previous ON identities (previous);
+CREATE INDEX identities_family ON identities (family);
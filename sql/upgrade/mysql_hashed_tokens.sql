@@ -0,0 +1,4 @@
+-- Hashed tokens (base64 SHA-256, 44 characters) no longer fit in the
+-- CHAR(32) token column of tables created by earlier versions, so it must be
+-- widened before upgrading (run_migrations does this as well)
+ALTER TABLE identities MODIFY token VARCHAR(64) NOT NULL;
@@ -0,0 +1,160 @@
+//! Client Address Extraction
+//!
+//! Determines the address of the client that made a request.  Forwarded
+//! headers are only honored when the request comes from a trusted proxy,
+//! as any client may set them.
+
+use std::net::{IpAddr, SocketAddr};
+
+use actix_web::HttpRequest;
+
+/// A network, in CIDR notation (e.g., `10.0.0.0/8` or `fd00::/8`)
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Cidr {
+    addr: IpAddr,
+    bits: u8,
+}
+
+impl Cidr {
+    /// Parses a network in CIDR notation.  A bare address is treated as a
+    /// network containing only that address
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - Network to parse (e.g., `192.168.0.0/16`)
+    pub fn parse(value: &str) -> Option<Cidr> {
+        let mut iter = value.trim().splitn(2, '/');
+        let addr: IpAddr = iter.next()?.parse().ok()?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+
+        let bits = match iter.next() {
+            Some(bits) => bits.parse().ok().filter(|bits| *bits <= max)?,
+            None => max,
+        };
+
+        Some(Cidr { addr, bits })
+    }
+
+    /// Checks if an address is within this network
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Address to check
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, *addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => prefix_eq(&net.octets(), &addr.octets(), self.bits),
+            (IpAddr::V6(net), IpAddr::V6(addr)) => prefix_eq(&net.octets(), &addr.octets(), self.bits),
+            _ => false,
+        }
+    }
+}
+
+/// Returns the address of the client that made a request.  Starting from
+/// the connected peer, each address in the forwarded headers is trusted
+/// only while the hop that added it is a trusted proxy
+///
+/// # Arguments
+///
+/// * `req` - The HTTP request recieved
+/// * `trusted` - Networks of the proxies allowed to forward requests
+pub(crate) fn client_addr<S>(req: &HttpRequest<S>, trusted: &[Cidr]) -> Option<IpAddr> {
+    let mut addr = req.peer_addr()?.ip();
+
+    for hop in forwarded_for(req).iter().rev() {
+        if !trusted.iter().any(|cidr| cidr.contains(&addr)) {
+            break;
+        }
+
+        match parse_addr(hop) {
+            Some(hop) => addr = hop,
+            None => break,
+        }
+    }
+
+    Some(addr)
+}
+
+/// Returns the client addresses listed in the `Forwarded` header, or the
+/// `X-Forwarded-For` header if there is none, from the original client to
+/// the last proxy
+///
+/// # Arguments
+///
+/// * `req` - The HTTP request recieved
+fn forwarded_for<S>(req: &HttpRequest<S>) -> Vec<String> {
+    let header = |name| {
+        req.headers()
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|value| value.trim().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    let forwarded: Vec<String> = header("Forwarded")
+        .iter()
+        .filter_map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| {
+                    let mut iter = pair.splitn(2, '=');
+                    match (iter.next(), iter.next()) {
+                        (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case("for") => {
+                            Some(value.trim().to_string())
+                        }
+                        _ => None,
+                    }
+                })
+                .next()
+        })
+        .collect();
+
+    if forwarded.is_empty() {
+        header("X-Forwarded-For")
+    } else {
+        forwarded
+    }
+}
+
+/// Parses an address, with or without a port (e.g., `192.0.2.1`,
+/// `192.0.2.1:8080`, `::1`, `[::1]:8080`, or `"[::1]"` as found in a
+/// `Forwarded` header)
+///
+/// # Arguments
+///
+/// * `value` - Address to parse
+pub(crate) fn parse_addr(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+
+    if let Ok(addr) = value.parse::<IpAddr>() {
+        return Some(addr);
+    }
+
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+
+    if value.starts_with('[') && value.ends_with(']') {
+        return value[1..value.len() - 1].parse().ok();
+    }
+
+    None
+}
+
+/// Compares the leading bits of two addresses
+///
+/// # Arguments
+///
+/// * `a` - Octets of the first address
+/// * `b` - Octets of the second address
+/// * `bits` - Number of leading bits to compare
+pub(crate) fn prefix_eq(a: &[u8], b: &[u8], bits: u8) -> bool {
+    let bits = bits as usize;
+
+    a.iter().zip(b).enumerate().all(|(i, (a, b))| {
+        let remaining = bits.saturating_sub(i * 8);
+        let mask = if remaining >= 8 { 0xff } else { !(0xffu8 >> remaining) };
+        a & mask == b & mask
+    })
+}
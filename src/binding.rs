@@ -0,0 +1,78 @@
+//! Session Binding
+//!
+//! Ties a session to the client it was used from, so a stolen token cannot
+//! be used from somewhere else.
+
+use std::net::IpAddr;
+
+use address::prefix_eq;
+use sql::SqlIdentityModel;
+
+/// Describes a property of the client a session is bound to.  Each
+/// request is compared against the client the session was last used from
+#[derive(Clone, Debug, PartialEq)]
+pub enum Binding {
+    /// The client address must match exactly
+    Ip,
+
+    /// The client address must be in the same subnet, given as the
+    /// number of leading bits to compare for IPv4 and IPv6 addresses
+    /// (e.g., `Subnet { v4: 24, v6: 64 }`)
+    Subnet { v4: u8, v6: u8 },
+
+    /// The `User-Agent` header must match exactly
+    UserAgent,
+}
+
+/// Describes what happens when a request does not match the client a
+/// session is bound to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BindingPolicy {
+    /// Treat the request as not authenticated (default)
+    Reject,
+
+    /// Log a warning, but accept the request
+    LogOnly,
+}
+
+impl Binding {
+    /// Checks a request against the client an identity was last used from.
+    /// Identities stored without the client details always match
+    ///
+    /// # Arguments
+    ///
+    /// * `ident` - Identity loaded from the backend provider
+    /// * `ip` - Address of the client making the request
+    /// * `ua` - User agent of the client making the request
+    pub(crate) fn matches(&self, ident: &SqlIdentityModel, ip: &str, ua: &str) -> bool {
+        match *self {
+            Binding::Ip => ident.ip.as_ref().map_or(true, |stored| stored == ip),
+            Binding::Subnet { v4, v6 } => ident
+                .ip
+                .as_ref()
+                .map_or(true, |stored| same_subnet(stored, ip, v4, v6)),
+            Binding::UserAgent => ident.useragent.as_ref().map_or(true, |stored| stored == ua),
+        }
+    }
+}
+
+/// Checks if two addresses are in the same subnet.  Addresses that cannot
+/// be parsed only match if they are identical
+///
+/// # Arguments
+///
+/// * `a` - First address
+/// * `b` - Second address
+/// * `v4` - Prefix length for IPv4 addresses
+/// * `v6` - Prefix length for IPv6 addresses
+fn same_subnet(a: &str, b: &str, v4: u8, v6: u8) -> bool {
+    match (a.parse::<IpAddr>(), b.parse::<IpAddr>()) {
+        (Ok(IpAddr::V4(a)), Ok(IpAddr::V4(b))) => {
+            prefix_eq(&a.octets(), &b.octets(), v4)
+        }
+        (Ok(IpAddr::V6(a)), Ok(IpAddr::V6(b))) => {
+            prefix_eq(&a.octets(), &b.octets(), v6)
+        }
+        _ => a == b,
+    }
+}
@@ -0,0 +1,180 @@
+//! Token Cache
+//!
+//! Keeps the identities of recently validated tokens in memory, so repeated
+//! requests can skip the database, and can still be authenticated while it
+//! is unavailable.  Each worker thread has its own cache, bounded to a
+//! number of tokens; the least recently used token is dropped when it is
+//! full.  Changes made by this thread invalidate cached tokens, changes made
+//! elsewhere are only seen once the cached tokens go stale.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::prelude::Utc;
+use chrono::{Duration, NaiveDateTime};
+
+use sql::{SqlIdentityModel, UpdateIdentity};
+use token;
+
+/// Identity cached for a token
+struct Entry {
+    ident: SqlIdentityModel,
+    validated: NaiveDateTime,
+    used: u64,
+}
+
+/// Cached identities, along with the order they were last used in
+struct Entries {
+    map: HashMap<String, Entry>,
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+/// Bounded cache of validated tokens, keyed by the hash of the raw token
+pub(crate) struct TokenCache {
+    capacity: usize,
+    entries: RefCell<Entries>,
+}
+
+impl TokenCache {
+    /// Creates an empty cache
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - Most tokens to keep at once
+    pub fn new(capacity: usize) -> TokenCache {
+        TokenCache {
+            capacity,
+            entries: RefCell::new(Entries {
+                map: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+            }),
+        }
+    }
+
+    /// Caches the identity a token was just validated against
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - Raw token, as provided by the client
+    /// * `ident` - Identity the token belongs to
+    pub fn insert(&self, token: &str, ident: &SqlIdentityModel) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let key = token::hash(token);
+        let mut entries = self.entries.borrow_mut();
+        entries.tick += 1;
+        let used = entries.tick;
+
+        if let Some(old) = entries.map.remove(&key) {
+            entries.order.remove(&old.used);
+        }
+
+        while entries.map.len() >= self.capacity {
+            let oldest = match entries.order.keys().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+
+            if let Some(key) = entries.order.remove(&oldest) {
+                entries.map.remove(&key);
+            }
+        }
+
+        entries.order.insert(used, key.clone());
+        entries.map.insert(
+            key,
+            Entry {
+                ident: ident.clone(),
+                validated: Utc::now().naive_utc(),
+                used,
+            },
+        );
+    }
+
+    /// Returns the identity cached for a token, if it was validated within
+    /// the given period and has not expired since.  Stale tokens are kept,
+    /// as they may still be used with a longer period
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - Raw token, as provided by the client
+    /// * `max_age` - How long ago the token may have been validated
+    pub fn get(&self, token: &str, max_age: Duration) -> Option<SqlIdentityModel> {
+        let key = token::hash(token);
+        let now = Utc::now().naive_utc();
+        let mut entries = self.entries.borrow_mut();
+        entries.tick += 1;
+        let used = entries.tick;
+
+        let (expired, fresh, old) = match entries.map.get_mut(&key) {
+            Some(entry) => {
+                let expired = entry.ident.expires.map_or(false, |exp| exp <= now);
+                let fresh = entry.validated + max_age > now;
+                let old = entry.used;
+                entry.used = used;
+                (expired, fresh, old)
+            }
+            None => return None,
+        };
+
+        entries.order.remove(&old);
+
+        if expired {
+            entries.map.remove(&key);
+            return None;
+        }
+
+        entries.order.insert(used, key.clone());
+
+        if fresh {
+            entries.map.get(&key).map(|entry| entry.ident.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Applies the last used time and client just saved for an identity to
+    /// its cached tokens, so the idle timeout and touch interval are checked
+    /// against what the database holds
+    ///
+    /// # Arguments
+    ///
+    /// * `update` - Changes saved to the identity
+    pub fn touch(&self, update: &UpdateIdentity) {
+        let mut entries = self.entries.borrow_mut();
+
+        for entry in entries.map.values_mut().filter(|entry| entry.ident.id == update.id) {
+            entry.ident.ip = update.ip.clone();
+            entry.ident.useragent = update.useragent.clone();
+            entry.ident.modified = update.modified;
+        }
+    }
+
+    /// Drops every cached identity matching a condition (e.g., after it
+    /// was deleted from the database)
+    ///
+    /// # Arguments
+    ///
+    /// * `condition` - Returns true for identities to drop
+    pub fn remove_where<F>(&self, condition: F)
+    where
+        F: Fn(&SqlIdentityModel) -> bool,
+    {
+        let mut entries = self.entries.borrow_mut();
+        let dropped: Vec<(String, u64)> = entries
+            .map
+            .iter()
+            .filter(|&(_, entry)| condition(&entry.ident))
+            .map(|(key, entry)| (key.clone(), entry.used))
+            .collect();
+
+        for (key, used) in dropped {
+            entries.map.remove(&key);
+            entries.order.remove(&used);
+        }
+    }
+}
@@ -0,0 +1,165 @@
+//! Errors
+//!
+//! Every failure surfaced to clients by this crate is described by a
+//! `SqlIdentityError`, so applications can map them to their own responses
+//! (see `SqlIdentityBuilder::error_handler`).
+
+use std::sync::Arc;
+
+use actix::MailboxError;
+
+// Actix Web imports
+use actix_web::error::{Error as ActixWebError, InternalError, ResponseError};
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+
+// Diesel (SQL ORM) Imports
+use diesel::result::Error as DieselError;
+
+// Failure (error management system) Imports
+use failure::Error;
+
+/// Function that builds the response for an error, or returns None to use
+/// the default response
+pub(crate) type ErrorHandler = Arc<Fn(&SqlIdentityError) -> Option<HttpResponse> + Send + Sync>;
+
+/// Error representing different failure cases
+#[derive(Debug, Fail)]
+pub enum SqlIdentityError {
+    /// The database variant was not compiled in
+    #[fail(display = "sql variant not supported")]
+    SqlVariantNotSupported,
+
+    /// No identity exists for the token provided
+    #[fail(display = "token not found")]
+    TokenNotFound,
+
+    /// The token provided has expired (or gone idle)
+    #[fail(display = "token expired")]
+    TokenExpired,
+
+    /// The request does not come from the client the session is bound to
+    #[fail(display = "session used from a different client")]
+    BindingMismatch,
+
+    /// The database could not be reached, or failed to run a query
+    #[fail(display = "identity backend unavailable")]
+    BackendUnavailable,
+
+    /// A newly issued token could not be placed in the response
+    #[fail(display = "token failed to set in header")]
+    TokenNotSet,
+
+    /// The request is not authenticated, but needs to be
+    #[fail(display = "token not provided but required, bad request")]
+    TokenRequired,
+
+    /// A refresh token was used more than once
+    #[fail(display = "refresh token reused, token family revoked")]
+    RefreshTokenReused,
+
+    /// The user has the most sessions allowed, and the eviction policy
+    /// rejects new ones
+    #[fail(display = "user has too many sessions")]
+    TooManySessions,
+
+    /// A trusted proxy network could not be parsed
+    #[fail(display = "invalid trusted proxy network: {}", _0)]
+    InvalidProxy(String),
+
+    /// The schema could not be created or upgraded
+    #[fail(display = "identity migration failed: {}", _0)]
+    MigrationFailed(String),
+
+    /// The identities table is missing, or does not have the columns
+    /// expected (every problem found is listed)
+    #[fail(display = "invalid identity schema: {}", _0)]
+    InvalidSchema(String),
+
+    /// A configured table or column name is not a plain identifier
+    #[fail(display = "invalid table or column name: {}", _0)]
+    InvalidName(String),
+}
+
+impl ResponseError for SqlIdentityError {
+    /// Returns the default response for an error
+    fn error_response(&self) -> HttpResponse {
+        let status = match *self {
+            SqlIdentityError::TokenNotFound
+            | SqlIdentityError::TokenExpired
+            | SqlIdentityError::BindingMismatch
+            | SqlIdentityError::TokenRequired
+            | SqlIdentityError::RefreshTokenReused => StatusCode::UNAUTHORIZED,
+            SqlIdentityError::TooManySessions => StatusCode::FORBIDDEN,
+            SqlIdentityError::BackendUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        HttpResponse::new(status)
+    }
+}
+
+impl SqlIdentityError {
+    /// Converts an error returned by the SQL actor.  Errors raised by this
+    /// crate are kept, a missing row means the token was not found, and
+    /// anything else means the database is unavailable
+    ///
+    /// # Arguments
+    ///
+    /// * `e` - Error returned by the SQL actor
+    pub(crate) fn from_backend(e: Error) -> SqlIdentityError {
+        let e = match e.downcast::<SqlIdentityError>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+
+        if let Some(&DieselError::NotFound) = e.downcast_ref() {
+            return SqlIdentityError::TokenNotFound;
+        }
+
+        error!("ERROR: {:?}", e);
+        SqlIdentityError::BackendUnavailable
+    }
+
+    /// Converts the result of a message sent to the SQL actor
+    ///
+    /// # Arguments
+    ///
+    /// * `res` - Result of sending a message to the SQL actor
+    pub(crate) fn from_sent<T>(res: Result<Result<T, Error>, MailboxError>) -> Result<T, SqlIdentityError> {
+        match res {
+            Ok(res) => res.map_err(SqlIdentityError::from_backend),
+            Err(e) => {
+                error!("ERROR: {:?}", e);
+                Err(SqlIdentityError::BackendUnavailable)
+            }
+        }
+    }
+
+    /// Converts an error into a response error, using the response built by
+    /// the error handler (if set and it returns one)
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - Error handler configured on the builder, if any
+    pub(crate) fn respond(self, handler: Option<&ErrorHandler>) -> ActixWebError {
+        match handler.and_then(|handler| handler(&self)) {
+            Some(resp) => InternalError::from_response(self, resp).into(),
+            None => self.into(),
+        }
+    }
+
+    /// Converts an error into a response error with the given status,
+    /// unless the error handler (if set) builds a response for it
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - Error handler configured on the builder, if any
+    /// * `status` - Status of the default response
+    pub(crate) fn respond_as(self, handler: Option<&ErrorHandler>, status: StatusCode) -> ActixWebError {
+        match handler.and_then(|handler| handler(&self)) {
+            Some(resp) => InternalError::from_response(self, resp).into(),
+            None => InternalError::new(self, status).into(),
+        }
+    }
+}
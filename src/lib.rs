@@ -55,31 +55,56 @@ extern crate actix;
 extern crate actix_web;
 extern crate base64;
 extern crate chrono;
+extern crate constant_time_eq;
+extern crate cookie;
 extern crate failure;
 extern crate futures;
 extern crate rand;
+extern crate serde;
+extern crate sha2;
+extern crate time;
 
 #[macro_use]
 extern crate diesel;
 #[macro_use]
 extern crate failure_derive;
 #[macro_use]
+extern crate lazy_static;
+#[macro_use]
 extern crate log;
+#[macro_use]
+extern crate serde_derive;
 
+mod address;
+mod binding;
+mod cache;
+mod error;
+mod memory;
+mod migrations;
+mod names;
+mod purge;
+mod query;
+mod refresh;
+mod schema;
+mod sessions;
 mod sql;
+pub mod store;
+mod token;
 
 use chrono::prelude::Utc;
-use chrono::NaiveDateTime;
+use chrono::{Duration, NaiveDateTime};
 
 use std::rc::Rc;
+use std::sync::Arc;
 
 use failure::Error;
 
-use actix::Addr;
+use actix::{Addr, MailboxError};
 
 // Actix Web imports
-use actix_web::error::{self, Error as ActixWebError};
+use actix_web::error::{Error as ActixWebError, InternalError};
 use actix_web::http::header::HeaderValue;
+use actix_web::http::Cookie;
 use actix_web::middleware::identity::{Identity, IdentityPolicy};
 use actix_web::middleware::Response as MiddlewareResponse;
 use actix_web::{HttpMessage, HttpRequest, HttpResponse};
@@ -88,30 +113,154 @@ use actix_web::{HttpMessage, HttpRequest, HttpResponse};
 use futures::future::{err as FutErr, ok as FutOk};
 use futures::Future;
 
+// Cookie Imports
+pub use cookie::SameSite;
+
+// (Local) Token Imports
+pub use token::TokenSource;
+
+// (Local) Address Imports
+use address::Cidr;
+
+// (Local) Binding Imports
+pub use binding::{Binding, BindingPolicy};
+
+// (Local) Cache Imports
+use cache::TokenCache;
+
+// (Local) Error Imports
+pub use error::SqlIdentityError;
+use error::ErrorHandler;
+
+// (Local) Name Imports
+pub use names::Column;
+use names::Names;
+
+// (Local) Purge Imports
+use purge::{PurgeActor, PurgeCallback};
+
+// (Local) Refresh Imports
+pub use refresh::RefreshHandler;
+use refresh::RefreshConfig;
+
+// (Local) Session Imports
+pub use sessions::{sessions_scope, RequestSessions, Session};
+use sessions::CurrentSession;
+
 // (Local) Sql Imports
-use sql::{DeleteIdentity, FindIdentity, SqlActor, SqlIdentityModel, UpdateIdentity, Variant};
+use sql::{FindIdentity, ListSessions, SqlActor, SqlIdentityModel, UpdateIdentity, Variant, KIND_ACCESS};
 
-// Rand Imports (thread secure!)
-use rand::Rng;
+// (Local) Store Imports
+use store::{Delete, DieselStore, IdentityStore, MemoryStore};
 
 const DEFAULT_RESPONSE_HDR: &'static str = "X-Actix-Auth";
+const DEFAULT_REFRESH_HDR: &'static str = "X-Actix-Refresh";
 const DEFAULT_POOL_SIZE: usize = 3;
+const DEFAULT_ROTATION_GRACE: i64 = 30; // seconds
+const DEFAULT_PURGE_BATCH: usize = 1000;
+const DEFAULT_CACHE_CAPACITY: usize = 10000;
+
+/// Describes the format of newly issued tokens.  Tokens of either format
+/// are always accepted, so the format may be changed without logging out
+/// existing sessions
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenFormat {
+    /// A single random value, looked up by its SHA-256 hash (default)
+    Hashed,
+
+    /// A `selector.verifier` pair.  The selector is used to look up the
+    /// identity, then the verifier is checked against its stored hash in
+    /// constant time
+    Split,
+}
+
+/// Describes what happens when a user logs in while already having the
+/// most sessions allowed (see `SqlIdentityBuilder::max_sessions_per_user`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EvictionPolicy {
+    /// Refuse the new login, responding with `403 Forbidden`
+    Reject,
+
+    /// Log out the least recently used session(s) to make room (default)
+    EvictLeastRecent,
+}
+
+/// Describes how requests are authenticated while the database is
+/// unavailable (see `SqlIdentityBuilder::failure_policy`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailurePolicy {
+    /// Handle requests as anonymous (default)
+    FailOpen,
+
+    /// Refuse requests that provide a token, responding with
+    /// `503 Service Unavailable`
+    FailClosed,
+
+    /// Accept tokens that were validated against the database within the
+    /// given period, and refuse any others as with `FailClosed`.  Changes
+    /// to identities are not saved until the database is available again
+    ServeCached(Duration),
+}
+
+/// Settings for the cookie used to transport tokens to and from browsers
+#[derive(Clone)]
+struct CookieConfig {
+    name: String,
+    path: String,
+    domain: Option<String>,
+    secure: bool,
+    http_only: bool,
+    same_site: SameSite,
+    max_age: Option<Duration>,
+}
+
+impl CookieConfig {
+    /// Creates a new set of cookie settings, with secure defaults
+    fn new() -> CookieConfig {
+        CookieConfig {
+            name: String::new(),
+            path: "/".to_string(),
+            domain: None,
+            secure: true,
+            http_only: true,
+            same_site: SameSite::Lax,
+            max_age: None,
+        }
+    }
+
+    /// Builds a cookie containing a token
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - Token to place in the cookie
+    fn build(&self, token: String) -> Cookie<'static> {
+        let mut cookie = Cookie::new(self.name.clone(), token);
+        cookie.set_path(self.path.clone());
+        cookie.set_secure(self.secure);
+        cookie.set_http_only(self.http_only);
+
+        if let Some(ref domain) = self.domain {
+            cookie.set_domain(domain.clone());
+        }
 
-/// Error representing different failure cases
-#[derive(Debug, Fail)]
-enum SqlIdentityError {
-    #[allow(dead_code)]
-    #[fail(display = "sql variant not supported")]
-    SqlVariantNotSupported,
+        if self.same_site != SameSite::None {
+            cookie.set_same_site(self.same_site);
+        }
 
-    #[fail(display = "token not found")]
-    TokenNotFound,
+        if let Some(max_age) = self.max_age {
+            cookie.set_max_age(time::Duration::seconds(max_age.num_seconds()));
+        }
 
-    #[fail(display = "token failed to set in header")]
-    TokenNotSet,
+        cookie
+    }
 
-    #[fail(display = "token not provided but required, bad request")]
-    TokenRequired,
+    /// Builds a cookie that removes the token cookie from a client
+    fn removal(&self) -> Cookie<'static> {
+        let mut cookie = self.build(String::new());
+        cookie.set_max_age(time::Duration::seconds(0));
+        cookie.set_expires(time::now() - time::Duration::days(365));
+        cookie
+    }
 }
 
 enum SqlIdentityState {
@@ -126,10 +275,28 @@ pub struct SqlIdentity {
     id: i64,
     state: SqlIdentityState,
     identity: Option<String>,
+    // Raw token for a newly remembered identity, or the stored (hashed)
+    // token for an identity loaded from the database
     token: Option<String>,
+    // Stored verifier hash, for an identity loaded with a split token
+    verifier: Option<String>,
+    // Raw refresh token for a newly remembered identity, if enabled
+    refresh: Option<String>,
+    // Tokens issued from the same login, for an identity loaded with an
+    // access token that has a refresh token
+    family: Option<String>,
+    // Id of the identity the request was authenticated with, when a new
+    // identity is remembered in its place
+    replaces: Option<i64>,
     ip: Option<String>,
     user_agent: Option<String>,
     created: NaiveDateTime,
+    // When the current token was issued (created, or last rotated)
+    issued: NaiveDateTime,
+    // When the identity was last saved, and whether the request came from
+    // a different address or user agent than the one stored
+    modified: NaiveDateTime,
+    moved: bool,
     inner: Rc<SqlIdentityInner>,
 }
 
@@ -145,12 +312,15 @@ impl Identity for SqlIdentity {
     ///
     /// * `value` - User to remember
     fn remember(&mut self, value: String) {
-        self.identity = Some(value);
+        // Never keep the token a request was authenticated with when a new
+        // identity is remembered (e.g., session fixation)
+        if self.id >= 0 {
+            self.replaces = Some(self.id);
+        }
 
-        // Generate a random token
-        let mut arr = [0u8; 24];
-        rand::thread_rng().fill(&mut arr[..]);
-        self.token = Some(base64::encode(&arr));
+        self.identity = Some(value);
+        self.token = Some(self.inner.generate());
+        self.refresh = self.inner.refresh.as_ref().map(|_| self.inner.generate());
 
         self.state = SqlIdentityState::Created;
     }
@@ -175,18 +345,24 @@ impl Identity for SqlIdentity {
 
             SqlIdentityState::Updated if self.token.is_some() && self.identity.is_some() => {
                 self.state = SqlIdentityState::Unchanged;
-                Ok(MiddlewareResponse::Future(self.inner.save(self, resp)))
+
+                if self.inner.rotation_due(self) {
+                    Ok(MiddlewareResponse::Future(self.inner.rotate(self, resp)))
+                } else if self.inner.touch_due(self) {
+                    Ok(MiddlewareResponse::Future(self.inner.save(self, resp)))
+                } else {
+                    Ok(MiddlewareResponse::Done(resp))
+                }
             }
 
             SqlIdentityState::Deleted if self.token.is_some() => {
-                let token = self.token.as_ref().expect("[SIS::Deleted] Token is None!");
                 self.state = SqlIdentityState::Unchanged;
-                Ok(MiddlewareResponse::Future(self.inner.remove(token, resp)))
+                Ok(MiddlewareResponse::Future(self.inner.remove(self, resp)))
             }
 
             SqlIdentityState::Deleted | SqlIdentityState::Updated => {
                 // Not logged in/log in failed
-                Err(error::ErrorBadRequest(SqlIdentityError::TokenRequired))
+                Err(self.inner.fail(SqlIdentityError::TokenRequired))
             }
 
             _ => {
@@ -201,6 +377,26 @@ impl Identity for SqlIdentity {
 struct SqlIdentityInner {
     addr: Addr<SqlActor>,
     hdr: &'static str,
+    max_age: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    delete_idle: bool,
+    legacy_tokens: bool,
+    token_format: TokenFormat,
+    cookie: Option<CookieConfig>,
+    sources: Vec<TokenSource>,
+    rotate_every: Option<Duration>,
+    rotation_grace: Duration,
+    refresh: Option<RefreshConfig>,
+    max_sessions: Option<usize>,
+    eviction: EvictionPolicy,
+    bindings: Vec<Binding>,
+    binding_policy: BindingPolicy,
+    trusted_proxies: Vec<Cidr>,
+    on_error: Option<ErrorHandler>,
+    failure: FailurePolicy,
+    cache: Option<Rc<TokenCache>>,
+    cache_ttl: Option<Duration>,
+    touch_interval: Option<Duration>,
 }
 
 impl SqlIdentityInner {
@@ -210,37 +406,140 @@ impl SqlIdentityInner {
     ///
     /// * `addr` - A SQL connection, already opened
     fn new(addr: Addr<SqlActor>, hdr: &'static str) -> SqlIdentityInner {
-        SqlIdentityInner { addr, hdr }
+        SqlIdentityInner {
+            addr,
+            hdr,
+            max_age: None,
+            idle_timeout: None,
+            delete_idle: false,
+            legacy_tokens: false,
+            token_format: TokenFormat::Hashed,
+            cookie: None,
+            sources: vec![TokenSource::Bearer],
+            rotate_every: None,
+            rotation_grace: Duration::seconds(DEFAULT_ROTATION_GRACE),
+            refresh: None,
+            max_sessions: None,
+            eviction: EvictionPolicy::EvictLeastRecent,
+            bindings: Vec::new(),
+            binding_policy: BindingPolicy::Reject,
+            trusted_proxies: Vec::new(),
+            on_error: None,
+            failure: FailurePolicy::FailOpen,
+            cache: None,
+            cache_ttl: None,
+            touch_interval: None,
+        }
+    }
+
+    /// Builds the response error for a failure, using the error handler if
+    /// one is set
+    ///
+    /// # Arguments
+    ///
+    /// * `e` - Failure to respond with
+    fn fail(&self, e: SqlIdentityError) -> ActixWebError {
+        e.respond(self.on_error.as_ref())
+    }
+
+    /// Checks if requests are served from the token cache while the
+    /// database is unavailable
+    fn serves_cached(&self) -> bool {
+        match self.failure {
+            FailurePolicy::ServeCached(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Drops identities from the token cache (if any), once they are
+    /// changed or deleted
+    ///
+    /// # Arguments
+    ///
+    /// * `condition` - Returns true for identities to drop
+    fn uncache<F: Fn(&SqlIdentityModel) -> bool>(&self, condition: F) {
+        if let Some(ref cache) = self.cache {
+            cache.remove_where(condition);
+        }
+    }
+
+    /// Generates a new token, in the configured format
+    fn generate(&self) -> String {
+        match self.token_format {
+            TokenFormat::Hashed => token::generate(),
+            TokenFormat::Split => token::generate_split(),
+        }
+    }
+
+    /// Places a newly issued token in the response header, and the token
+    /// cookie (if configured)
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - Raw token to hand to the client
+    /// * `resp` - HTTP response to modify
+    fn set_token(&self, token: &str, resp: &mut HttpResponse) -> Result<(), ActixWebError> {
+        if let Ok(value) = token.parse::<HeaderValue>() {
+            resp.headers_mut().append(self.hdr, value);
+        } else {
+            error!("Failed to parse token to place in header!");
+            return Err(self.fail(SqlIdentityError::TokenNotSet));
+        }
+
+        if let Some(ref cookie) = self.cookie {
+            if let Err(e) = resp.add_cookie(&cookie.build(token.to_string())) {
+                error!("Failed to place token in cookie: {:?}", e);
+                return Err(self.fail(SqlIdentityError::TokenNotSet));
+            }
+        }
+
+        Ok(())
     }
 
     fn create(&self, identity: &SqlIdentity, mut resp: HttpResponse) -> Box<Future<Item = HttpResponse, Error = ActixWebError>> {
         if let Some(ref token) = identity.token {
-            let headers = resp.headers_mut();
-
-            if let Ok(token) = token.parse() {
-                headers.append(self.hdr, token);
-            } else {
-                error!("Failed to parse token to place in header!");
-                return Box::new(FutErr(error::ErrorInternalServerError(
-                    SqlIdentityError::TokenNotSet,
-                )));
+            if let Err(e) = self.set_token(token, &mut resp) {
+                return Box::new(FutErr(e));
             }
         } else {
             error!("Identity token not set!");
-            return Box::new(FutErr(error::ErrorUnauthorized(
-                SqlIdentityError::TokenNotFound,
-            )));
+            return Box::new(FutErr(self.fail(SqlIdentityError::TokenNotFound)));
+        }
+
+        if let Some(replaces) = identity.replaces {
+            self.uncache(|i| i.id == replaces);
         }
 
+        // With refresh tokens enabled, the access token is stored together
+        // with a refresh token, in a new family
+        let session = match (self.refresh.as_ref(), identity.refresh.as_ref()) {
+            (Some(config), Some(refresh)) => {
+                if let Err(e) = config.set_token(self, refresh, &mut resp) {
+                    return Box::new(FutErr(e));
+                }
+
+                config.pair(identity, refresh)
+            }
+            _ => UpdateIdentity::session(identity),
+        };
+
+        let inner = Rc::clone(&identity.inner);
+
         Box::new(
             self.addr
-                .send(UpdateIdentity::create(identity))
-                .map_err(ActixWebError::from)
-                .and_then(move |res| match res {
-                    Ok(_) => Ok(resp),
+                .send(session)
+                .then(move |res| match SqlIdentityError::from_sent(res) {
+                    Ok(evicted) => {
+                        // Sessions evicted to stay within the limit
+                        if !evicted.is_empty() {
+                            inner.uncache(|i| evicted.contains(&i.id));
+                        }
+
+                        Ok(resp)
+                    }
                     Err(e) => {
-                        error!("ERROR: {:?}", e);
-                        Err(error::ErrorInternalServerError(e))
+                        warn!("WARN: {}", e);
+                        Err(inner.fail(e))
                     }
                 }),
         )
@@ -253,38 +552,192 @@ impl SqlIdentityInner {
         resp: HttpResponse,
     ) -> Box<Future<Item = HttpResponse, Error = ActixWebError>> {
 
+        let inner = Rc::clone(&identity.inner);
+        let update = UpdateIdentity::update(identity);
+
         Box::new(
             self.addr
-                .send(UpdateIdentity::update(identity))
-                .map_err(ActixWebError::from)
-                .and_then(move |res| match res {
-                    Ok(_) => Ok(resp),
-                    Err(e) => {
-                        error!("ERROR: {:?}", e);
-                        Err(error::ErrorInternalServerError(e))
+                .send(update.clone())
+                .then(move |res| match SqlIdentityError::from_sent(res) {
+                    Ok(_) => {
+                        if let Some(ref cache) = inner.cache {
+                            cache.touch(&update);
+                        }
+
+                        Ok(resp)
                     }
+                    Err(SqlIdentityError::BackendUnavailable) if inner.serves_cached() => Ok(resp),
+                    Err(e) => Err(inner.fail(e)),
                 }),
         )
     }
 
-    /// Removes an identity from the backend provider (SQL database)
+    /// Checks if the token of an identity is old enough to be rotated
+    ///
+    /// # Arguments
+    ///
+    /// * `identity` - Identity loaded for the current request
+    fn rotation_due(&self, identity: &SqlIdentity) -> bool {
+        self.rotate_every
+            .map_or(false, |every| identity.issued + every <= Utc::now().naive_utc())
+    }
+
+    /// Checks if the last used time (or client) of an identity needs to be
+    /// saved, because it is older than the touch interval or the client
+    /// changed
+    ///
+    /// # Arguments
+    ///
+    /// * `identity` - Identity loaded for the current request
+    fn touch_due(&self, identity: &SqlIdentity) -> bool {
+        identity.moved
+            || self.touch_interval
+                .map_or(true, |every| identity.modified + every <= Utc::now().naive_utc())
+    }
+
+    /// Replaces the token of an identity with a newly issued one, handing
+    /// the new token to the client.  The previous token remains valid for
+    /// the rotation grace period, so parallel requests made with it do not
+    /// fail.  If another request already rotated the token, nothing is done
+    fn rotate(
+        &self,
+        identity: &SqlIdentity,
+        mut resp: HttpResponse,
+    ) -> Box<Future<Item = HttpResponse, Error = ActixWebError>> {
+        let current = identity.token.as_ref().map(|s| s.as_ref()).unwrap_or("");
+        let token = match identity.verifier {
+            Some(_) => format!("{}.{}", current, token::generate()),
+            None => token::generate(),
+        };
+
+        let inner = Rc::clone(&identity.inner);
+        let id = identity.id;
+
+        Box::new(
+            self.addr
+                .send(UpdateIdentity::rotate(identity, &token))
+                .then(move |res| match SqlIdentityError::from_sent(res) {
+                    Ok(0) => {
+                        info!("Token already rotated by another request");
+                        Ok(resp)
+                    }
+                    Ok(_) => {
+                        inner.uncache(|i| i.id == id);
+                        inner.set_token(&token, &mut resp)?;
+                        Ok(resp)
+                    }
+                    Err(SqlIdentityError::BackendUnavailable) if inner.serves_cached() => Ok(resp),
+                    Err(e) => Err(inner.fail(e)),
+                }),
+        )
+    }
+
+    /// Removes an identity from the backend provider (SQL database),
+    /// along with any refresh tokens issued with it
     fn remove(
         &self,
-        token: &str,
-        resp: HttpResponse,
+        identity: &SqlIdentity,
+        mut resp: HttpResponse,
     ) -> Box<Future<Item = HttpResponse, Error = ActixWebError>> {
+        if let Some(ref cookie) = self.cookie {
+            if let Err(e) = resp.add_cookie(&cookie.removal()) {
+                error!("Failed to clear token cookie: {:?}", e);
+            }
+        }
+
+        let id = identity.id;
+        let family = identity.family.clone();
+        self.uncache(|i| i.id == id || (family.is_some() && i.family == family));
+
+        let sent: Box<Future<Item = Result<usize, Error>, Error = MailboxError>> =
+            match identity.family {
+                Some(ref family) => Box::new(self.addr.send(Delete::Family(family.clone()))),
+                None => Box::new(self.addr.send(Delete::Token(
+                    identity.token.clone().unwrap_or_default(),
+                ))),
+            };
+
+        let on_error = self.on_error.clone();
+
+        Box::new(sent.then(move |res| match SqlIdentityError::from_sent(res) {
+            Ok(_) => Ok(resp),
+            Err(e) => Err(e.respond(on_error.as_ref())),
+        }))
+    }
+
+    /// Deletes every identity belonging to a user from the backend
+    /// provider (SQL database), returning how many tokens were deleted
+    ///
+    /// # Arguments
+    ///
+    /// * `userid` - User to delete the identities of
+    /// * `keep` - Id of an identity to keep, if any
+    fn delete_all(
+        &self,
+        userid: String,
+        keep: Option<i64>,
+    ) -> Box<Future<Item = usize, Error = ActixWebError>> {
+        self.uncache(|i| i.userid == userid && Some(i.id) != keep);
+
+        let on_error = self.on_error.clone();
+
         Box::new(
             self.addr
-                .send(DeleteIdentity {
-                    token: token.to_string(),
-                })
-                .map_err(ActixWebError::from)
-                .and_then(move |res| match res {
-                    Ok(_) => Ok(resp),
-                    Err(e) => {
-                        error!("ERROR: {:?}", e);
-                        Err(error::ErrorInternalServerError(e))
+                .send(Delete::User { userid, keep })
+                .then(move |res| SqlIdentityError::from_sent(res).map_err(|e| e.respond(on_error.as_ref()))),
+        )
+    }
+
+    /// Lists the identities of a user that are still valid, most recently
+    /// used first
+    ///
+    /// # Arguments
+    ///
+    /// * `userid` - User to list the identities of
+    /// * `current` - Id of the identity of the current request, if any
+    fn list(
+        &self,
+        userid: String,
+        current: Option<i64>,
+    ) -> Box<Future<Item = Vec<Session>, Error = ActixWebError>> {
+        let idle_timeout = self.idle_timeout;
+        let on_error = self.on_error.clone();
+
+        Box::new(
+            self.addr
+                .send(ListSessions { userid })
+                .then(move |res| match SqlIdentityError::from_sent(res) {
+                    Ok(sessions) => {
+                        let now = Utc::now().naive_utc();
+
+                        Ok(sessions
+                            .into_iter()
+                            .filter(|s| idle_timeout.map_or(true, |timeout| s.modified + timeout > now))
+                            .map(|s| Session::from_model(s, current))
+                            .collect())
                     }
+                    Err(e) => Err(e.respond(on_error.as_ref())),
+                }),
+        )
+    }
+
+    /// Revokes a single identity of a user, returning true if it existed
+    ///
+    /// # Arguments
+    ///
+    /// * `userid` - User the identity belongs to
+    /// * `id` - Id of the identity to revoke
+    fn revoke(&self, userid: String, id: i64) -> Box<Future<Item = bool, Error = ActixWebError>> {
+        self.uncache(|i| i.id == id && i.userid == userid);
+
+        let on_error = self.on_error.clone();
+
+        Box::new(
+            self.addr
+                .send(Delete::Session { userid, id })
+                .then(move |res| match SqlIdentityError::from_sent(res) {
+                    Ok(n) => Ok(n > 0),
+                    Err(e) => Err(e.respond(on_error.as_ref())),
                 }),
         )
     }
@@ -293,41 +746,159 @@ impl SqlIdentityInner {
     fn load<S>(
         &self,
         req: &HttpRequest<S>,
-    ) -> Box<Future<Item = Option<SqlIdentityModel>, Error = ActixWebError>> {
-        let headers = req.headers();
-        let auth_header = headers.get("Authorization");
-
-        if let Some(auth_header) = auth_header {
-            // Return the identity (or none, if it doesn't exist)
-
-            if let Ok(auth_header) = auth_header.to_str() {
-                let mut iter = auth_header.split(' ');
-                let scheme = iter.next();
-                let token = iter.next();
-
-                if scheme.is_some() && token.is_some() {
-                    let _scheme = scheme.expect("[SII::load] Scheme is None!");
-                    let token = token.expect("[SII::load] Token is None!");
-
-                    return Box::new(
-                        self.addr
-                            .send(FindIdentity {
-                                token: token.to_string(),
-                            })
-                            .map_err(ActixWebError::from)
-                            .and_then(move |res| match res {
-                                Ok(val) => Ok(Some(val)),
-                                Err(e) => {
-                                    warn!("WARN: {:?}", e);
-                                    Ok(None)
+    ) -> Box<Future<Item = Result<Option<SqlIdentityModel>, SqlIdentityError>, Error = ActixWebError>> {
+        // Use the first source that provides a token, in the order configured
+        for source in &self.sources {
+            if let Some(token) = source.extract(req) {
+                return self.find(&token);
+            }
+        }
+
+        Box::new(FutOk(Ok(None)))
+    }
+
+    /// Looks up the identity belonging to a token provided by a client.
+    /// Split (selector.verifier) tokens are looked up by their selector and
+    /// then verified, all other tokens are looked up by their hash.  A token
+    /// that was recently rotated is still accepted during the grace period.
+    /// Tokens validated within the cache TTL are taken from the token cache
+    /// without a database round trip.  While the database is unavailable,
+    /// the token cache is used instead (if configured to do so)
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - Raw token, as provided by the client
+    fn find(
+        &self,
+        token: &str,
+    ) -> Box<Future<Item = Result<Option<SqlIdentityModel>, SqlIdentityError>, Error = ActixWebError>> {
+        if let (Some(ttl), Some(cache)) = (self.cache_ttl, self.cache.as_ref()) {
+            if let Some(val) = cache.get(token, ttl) {
+                return Box::new(FutOk(Ok(Some(val))));
+            }
+        }
+
+        let lookup = token::Lookup::new(token, self.legacy_tokens);
+        let grace = self.rotation_grace;
+        let failure = self.failure;
+        let cache = self.cache.clone();
+        let presented = token.to_owned();
+
+        Box::new(
+            self.addr
+                .send(FindIdentity {
+                    tokens: lookup.tokens.clone(),
+                    kind: KIND_ACCESS,
+                })
+                .then(move |res| {
+                    Ok(match SqlIdentityError::from_sent(res) {
+                        Ok(val) => {
+                            if lookup.matches(&val, grace) {
+                                // A previous token is only accepted until
+                                // its grace period ends, so is never cached
+                                if let Some(ref cache) = cache {
+                                    if lookup.is_current(&val) {
+                                        cache.insert(&presented, &val);
+                                    }
                                 }
-                            }),
-                    );
+
+                                Ok(Some(val))
+                            } else {
+                                warn!("WARN: token verification failed for identity {}", val.id);
+                                Err(SqlIdentityError::TokenNotFound)
+                            }
+                        }
+                        Err(SqlIdentityError::BackendUnavailable) => {
+                            let cached = match (failure, cache) {
+                                (FailurePolicy::ServeCached(period), Some(ref cache)) => {
+                                    cache.get(&presented, period)
+                                }
+                                _ => None,
+                            };
+
+                            match cached {
+                                Some(val) => {
+                                    warn!("WARN: backend unavailable, identity {} served from cache", val.id);
+                                    Ok(Some(val))
+                                }
+                                None => Err(SqlIdentityError::BackendUnavailable),
+                            }
+                        }
+                        Err(e) => {
+                            warn!("WARN: {}", e);
+                            Err(e)
+                        }
+                    })
+                }),
+        )
+    }
+
+    /// Checks if an identity has been idle (not used) for longer than the
+    /// configured idle timeout.  Returns the identity if it is still active,
+    /// or an error if it has gone idle (deleting it, if configured to do so)
+    ///
+    /// # Arguments
+    ///
+    /// * `ident` - Identity loaded from the backend provider
+    fn check_idle(&self, ident: SqlIdentityModel) -> Result<SqlIdentityModel, SqlIdentityError> {
+        if let Some(timeout) = self.idle_timeout {
+            if ident.modified + timeout <= Utc::now().naive_utc() {
+                info!("Identity {} has gone idle", ident.id);
+                self.uncache(|i| i.id == ident.id);
+
+                if self.delete_idle {
+                    self.addr.do_send(Delete::Token(ident.token));
+                }
+
+                return Err(SqlIdentityError::TokenExpired);
+            }
+        }
+
+        Ok(ident)
+    }
+
+    /// Returns the address (without port) and user agent of the client that
+    /// made a request
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The HTTP request recieved
+    fn client_info<S>(&self, req: &HttpRequest<S>) -> (String, String) {
+        let conn_ip = address::client_addr(req, &self.trusted_proxies)
+            .map_or_else(|| "0.0.0.0".to_owned(), |addr| addr.to_string());
+
+        let unk = HeaderValue::from_static("Unknown");
+        let ua = req.headers()
+            .get("user-agent")
+            .unwrap_or(&unk)
+            .to_str()
+            .unwrap_or("Unknown")
+            .to_owned();
+
+        (conn_ip, ua)
+    }
+
+    /// Checks if a request comes from the client an identity is bound to.
+    /// Returns the identity if it matches (or mismatches are only logged),
+    /// or an error if it does not
+    ///
+    /// # Arguments
+    ///
+    /// * `ident` - Identity loaded from the backend provider
+    /// * `ip` - Address of the client making the request
+    /// * `ua` - User agent of the client making the request
+    fn check_binding(&self, ident: SqlIdentityModel, ip: &str, ua: &str) -> Result<SqlIdentityModel, SqlIdentityError> {
+        for binding in &self.bindings {
+            if !binding.matches(&ident, ip, ua) {
+                warn!("Identity {} used from a different client ({:?})", ident.id, binding);
+
+                if self.binding_policy == BindingPolicy::Reject {
+                    return Err(SqlIdentityError::BindingMismatch);
                 }
             }
         }
 
-        Box::new(FutOk(None))
+        Ok(ident)
     }
 }
 
@@ -335,12 +906,96 @@ impl SqlIdentityInner {
 #[derive(Clone)]
 pub struct SqlIdentityPolicy(Rc<SqlIdentityInner>);
 
+impl SqlIdentityPolicy {
+    /// Returns a handler that exchanges refresh tokens for new access and
+    /// refresh token pairs, to be mounted on a route (e.g., `/refresh`).
+    /// Only available when refresh tokens are enabled with
+    /// `SqlIdentityBuilder::refresh_tokens`; otherwise the handler responds
+    /// with `404 Not Found` (unless the error handler builds a response for
+    /// `SqlIdentityError::TokenNotFound`)
+    pub fn refresh_handler(&self) -> RefreshHandler {
+        RefreshHandler(Rc::clone(&self.0))
+    }
+
+    /// Deletes every session of a user (e.g., after a password change or
+    /// when an account is disabled).  Resolves to the number of tokens
+    /// deleted.  To do this for the user making a request, while keeping
+    /// its session alive, see `RequestSessions::forget_everywhere`
+    ///
+    /// # Arguments
+    ///
+    /// * `userid` - User to log out everywhere
+    pub fn delete_all_for_user<T: Into<String>>(
+        &self,
+        userid: T,
+    ) -> Box<Future<Item = usize, Error = ActixWebError>> {
+        self.0.delete_all(userid.into(), None)
+    }
+
+    /// Lists the sessions of a user that are still valid, most recently
+    /// used first (e.g., for an "active devices" page).  To list the
+    /// sessions of the user making a request, see `RequestSessions::sessions`
+    ///
+    /// # Arguments
+    ///
+    /// * `userid` - User to list the sessions of
+    pub fn list_sessions<T: Into<String>>(
+        &self,
+        userid: T,
+    ) -> Box<Future<Item = Vec<Session>, Error = ActixWebError>> {
+        self.0.list(userid.into(), None)
+    }
+
+    /// Revokes a single session of a user by its id.  Resolves to true if
+    /// the session existed
+    ///
+    /// # Arguments
+    ///
+    /// * `userid` - User the session belongs to
+    /// * `id` - Id of the session, as listed by `list_sessions`
+    pub fn revoke_session<T: Into<String>>(
+        &self,
+        userid: T,
+        id: i64,
+    ) -> Box<Future<Item = bool, Error = ActixWebError>> {
+        self.0.revoke(userid.into(), id)
+    }
+}
+
 #[derive(Clone)]
 pub struct SqlIdentityBuilder {
     pool: usize,
     uri: String,
     hdr: &'static str,
     variant: Variant,
+    max_age: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    delete_idle: bool,
+    legacy_tokens: bool,
+    token_format: TokenFormat,
+    cookie: Option<String>,
+    cookie_opts: CookieConfig,
+    sources: Option<Vec<TokenSource>>,
+    rotate_every: Option<Duration>,
+    rotation_grace: Duration,
+    refresh: Option<(Duration, Duration)>,
+    refresh_hdr: &'static str,
+    max_sessions: Option<usize>,
+    eviction: EvictionPolicy,
+    purge_every: Option<Duration>,
+    purge_batch: usize,
+    on_purge: Option<PurgeCallback>,
+    bindings: Vec<Binding>,
+    binding_policy: BindingPolicy,
+    trusted_proxies: Vec<String>,
+    on_error: Option<ErrorHandler>,
+    failure: FailurePolicy,
+    cache: Option<(usize, Duration)>,
+    touch_interval: Option<Duration>,
+    store: Option<Arc<IdentityStore>>,
+    migrate: bool,
+    validate: bool,
+    names: Names,
 }
 
 impl SqlIdentityBuilder {
@@ -380,6 +1035,34 @@ impl SqlIdentityBuilder {
             uri: uri,
             hdr: DEFAULT_RESPONSE_HDR,
             variant: variant,
+            max_age: None,
+            idle_timeout: None,
+            delete_idle: false,
+            legacy_tokens: false,
+            token_format: TokenFormat::Hashed,
+            cookie: None,
+            cookie_opts: CookieConfig::new(),
+            sources: None,
+            rotate_every: None,
+            rotation_grace: Duration::seconds(DEFAULT_ROTATION_GRACE),
+            refresh: None,
+            refresh_hdr: DEFAULT_REFRESH_HDR,
+            max_sessions: None,
+            eviction: EvictionPolicy::EvictLeastRecent,
+            purge_every: None,
+            purge_batch: DEFAULT_PURGE_BATCH,
+            on_purge: None,
+            bindings: Vec::new(),
+            binding_policy: BindingPolicy::Reject,
+            trusted_proxies: Vec::new(),
+            on_error: None,
+            failure: FailurePolicy::FailOpen,
+            cache: None,
+            touch_interval: None,
+            store: None,
+            migrate: false,
+            validate: false,
+            names: Names::new(),
         }
     }
 
@@ -388,11 +1071,33 @@ impl SqlIdentityBuilder {
             return Variant::Mysql;
         } else if uri.starts_with("postgres://") || uri.starts_with("postgresql://") {
             return Variant::Pg;
+        } else if uri.starts_with("memory://") {
+            return Variant::Memory;
         } else {
             return Variant::Sqlite;
         }
     }
 
+    /// Runs the migrations on a SQL store, then checks its schema, if
+    /// enabled
+    ///
+    /// # Arguments
+    ///
+    /// * `store` - Store opened with the connection string
+    fn migrated(&self, store: DieselStore) -> Result<Arc<IdentityStore>, Error> {
+        let store = store.renamed(self.names.clone());
+
+        if self.migrate {
+            migrations::once(&self.uri, &self.names, || store.migrate())?;
+        }
+
+        if self.validate {
+            store.validate()?;
+        }
+
+        Ok(Arc::new(store))
+    }
+
     /// Change the response header when an identity is remembered
     ///
     /// # Arguments
@@ -413,6 +1118,502 @@ impl SqlIdentityBuilder {
         self
     }
 
+    /// Set the absolute lifetime of a newly remembered identity.  Once
+    /// this much time has passed since the token was issued, the token
+    /// is treated as if it does not exist.  By default, tokens never expire
+    ///
+    /// # Arguments
+    ///
+    /// * `age` - How long a token is valid for after it is created
+    pub fn max_age(mut self, age: Duration) -> SqlIdentityBuilder {
+        self.max_age = Some(age);
+        self
+    }
+
+    /// Set how long an identity may go unused before it is no longer
+    /// valid.  Every request made with a token refreshes its last used
+    /// time, so active sessions are kept alive.  By default, identities
+    /// never go idle
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - How long a token may go unused
+    pub fn idle_timeout(mut self, timeout: Duration) -> SqlIdentityBuilder {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Delete identities from the database when they are found to be idle,
+    /// instead of just ignoring them.  Only has an effect when an idle
+    /// timeout is set
+    ///
+    /// # Arguments
+    ///
+    /// * `delete` - True to delete idle identities
+    pub fn delete_idle(mut self, delete: bool) -> SqlIdentityBuilder {
+        self.delete_idle = delete;
+        self
+    }
+
+    /// Accept tokens that were stored in plaintext by earlier versions of
+    /// this crate.  New tokens are always stored hashed; this only allows
+    /// existing (legacy) sessions to keep working until they expire or are
+    /// removed, and should be disabled once they are gone
+    ///
+    /// # Arguments
+    ///
+    /// * `accept` - True to accept legacy plaintext tokens
+    pub fn legacy_tokens(mut self, accept: bool) -> SqlIdentityBuilder {
+        self.legacy_tokens = accept;
+        self
+    }
+
+    /// Change the format of newly issued tokens (default: `TokenFormat::Hashed`)
+    ///
+    /// # Arguments
+    ///
+    /// * `format` - Format to use when issuing tokens
+    pub fn token_format(mut self, format: TokenFormat) -> SqlIdentityBuilder {
+        self.token_format = format;
+        self
+    }
+
+    /// Transport tokens in a cookie (in addition to the response header),
+    /// for use by browser applications.  The cookie is set when an identity
+    /// is remembered, cleared when it is forgotten, and (unless the token
+    /// sources are overridden) read on requests that do not provide an
+    /// `Authorization` header
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the cookie
+    pub fn cookie<T: Into<String>>(mut self, name: T) -> SqlIdentityBuilder {
+        self.cookie = Some(name.into());
+        self
+    }
+
+    /// Set the `Secure` attribute of the token cookie (default: true)
+    ///
+    /// # Arguments
+    ///
+    /// * `secure` - True to only send the cookie over HTTPS
+    pub fn cookie_secure(mut self, secure: bool) -> SqlIdentityBuilder {
+        self.cookie_opts.secure = secure;
+        self
+    }
+
+    /// Set the `HttpOnly` attribute of the token cookie (default: true)
+    ///
+    /// # Arguments
+    ///
+    /// * `http_only` - True to hide the cookie from JavaScript
+    pub fn cookie_http_only(mut self, http_only: bool) -> SqlIdentityBuilder {
+        self.cookie_opts.http_only = http_only;
+        self
+    }
+
+    /// Set the `SameSite` attribute of the token cookie (default:
+    /// `SameSite::Lax`).  The cookie is read on every request, so without
+    /// this attribute browsers also send it on requests from other sites,
+    /// leaving the application open to cross-site request forgery
+    ///
+    /// # Arguments
+    ///
+    /// * `same_site` - SameSite policy of the cookie (`SameSite::None` omits it)
+    pub fn cookie_same_site(mut self, same_site: SameSite) -> SqlIdentityBuilder {
+        self.cookie_opts.same_site = same_site;
+        self
+    }
+
+    /// Set the `Path` attribute of the token cookie (default: `/`)
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path the cookie is valid for
+    pub fn cookie_path<T: Into<String>>(mut self, path: T) -> SqlIdentityBuilder {
+        self.cookie_opts.path = path.into();
+        self
+    }
+
+    /// Set the `Domain` attribute of the token cookie (default: not set)
+    ///
+    /// # Arguments
+    ///
+    /// * `domain` - Domain the cookie is valid for
+    pub fn cookie_domain<T: Into<String>>(mut self, domain: T) -> SqlIdentityBuilder {
+        self.cookie_opts.domain = Some(domain.into());
+        self
+    }
+
+    /// Set the `Max-Age` attribute of the token cookie (default: not set,
+    /// the cookie is kept until the browser is closed)
+    ///
+    /// # Arguments
+    ///
+    /// * `age` - How long the browser should keep the cookie
+    pub fn cookie_max_age(mut self, age: Duration) -> SqlIdentityBuilder {
+        self.cookie_opts.max_age = Some(age);
+        self
+    }
+
+    /// Change where tokens are read from in a request.  Sources are tried
+    /// in order, and the first one that provides a token is used.  By
+    /// default, the `Authorization` header (bearer scheme) is checked,
+    /// followed by the token cookie if one is set
+    ///
+    /// # Arguments
+    ///
+    /// * `sources` - Sources to read tokens from, in order
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # extern crate actix_web_sql_identity;
+    /// use actix_web_sql_identity::{SqlIdentityBuilder, TokenSource};
+    ///
+    /// // Allow WebSocket upgrades to pass the token in the query string
+    /// let policy = SqlIdentityBuilder::new("sqlite://my.db")
+    ///                 .token_sources(vec![
+    ///                     TokenSource::Bearer,
+    ///                     TokenSource::Query("access_token".to_string()),
+    ///                 ]);
+    /// ```
+    pub fn token_sources<I>(mut self, sources: I) -> SqlIdentityBuilder
+    where
+        I: IntoIterator<Item = TokenSource>,
+    {
+        self.sources = Some(sources.into_iter().collect());
+        self
+    }
+
+    /// Automatically replace tokens once they reach a certain age.  The
+    /// new token is returned in the response header (and cookie, if set)
+    /// of the first request made after the token becomes due for rotation.
+    /// By default, tokens are never rotated
+    ///
+    /// # Arguments
+    ///
+    /// * `every` - How old a token may get before it is replaced
+    pub fn rotate_every(mut self, every: Duration) -> SqlIdentityBuilder {
+        self.rotate_every = Some(every);
+        self
+    }
+
+    /// Change how long a token remains valid after it has been rotated, to
+    /// allow requests that were already in flight to finish (default: 30
+    /// seconds)
+    ///
+    /// # Arguments
+    ///
+    /// * `grace` - How long a rotated token remains valid
+    pub fn rotation_grace(mut self, grace: Duration) -> SqlIdentityBuilder {
+        self.rotation_grace = grace;
+        self
+    }
+
+    /// Issue a short-lived access token and a long-lived refresh token when
+    /// an identity is remembered.  Only the access token is accepted on
+    /// requests; the refresh token is returned in the refresh header, and
+    /// may be exchanged for a new pair at the handler returned by
+    /// `SqlIdentityPolicy::refresh_handler`.  Overrides `max_age` for
+    /// access tokens
+    ///
+    /// # Arguments
+    ///
+    /// * `access_ttl` - How long an access token is valid for
+    /// * `refresh_ttl` - How long a refresh token is valid for
+    pub fn refresh_tokens(mut self, access_ttl: Duration, refresh_ttl: Duration) -> SqlIdentityBuilder {
+        self.refresh = Some((access_ttl, refresh_ttl));
+        self
+    }
+
+    /// Change the header refresh tokens are returned in, and may be
+    /// provided in when refreshing (default: `X-Actix-Refresh`)
+    ///
+    /// # Arguments
+    ///
+    /// * `hdr` - Refresh header name to use
+    pub fn refresh_header(mut self, hdr: &'static str) -> SqlIdentityBuilder {
+        self.refresh_hdr = hdr;
+        self
+    }
+
+    /// Limit how many sessions (logged in devices) a user may have at once.
+    /// When a user with the most sessions allowed logs in again, the
+    /// eviction policy decides what happens.  With refresh tokens, a session
+    /// counts until its refresh token expires, as it can still be refreshed
+    /// after its access token has.  By default, sessions are not limited
+    ///
+    /// # Arguments
+    ///
+    /// * `max` - Most sessions a user may have at once
+    pub fn max_sessions_per_user(mut self, max: usize) -> SqlIdentityBuilder {
+        self.max_sessions = Some(max);
+        self
+    }
+
+    /// Change what happens when a user logs in while already having the
+    /// most sessions allowed (default: `EvictionPolicy::EvictLeastRecent`)
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - Eviction policy to use
+    pub fn eviction_policy(mut self, policy: EvictionPolicy) -> SqlIdentityBuilder {
+        self.eviction = policy;
+        self
+    }
+
+    /// Periodically delete expired identities (and idle ones, if an idle
+    /// timeout is set) from the database, in the background.  The purge is
+    /// started by `finish`, once per process for each connection string and
+    /// table (or custom store), however many workers build the policy.  By
+    /// default, expired identities are never deleted
+    ///
+    /// # Arguments
+    ///
+    /// * `every` - How often to purge expired identities
+    pub fn purge_every(mut self, every: Duration) -> SqlIdentityBuilder {
+        self.purge_every = Some(every);
+        self
+    }
+
+    /// Change how many identities are deleted at once when purging, to
+    /// keep each delete (and the locks it holds) short (default: 1000)
+    ///
+    /// # Arguments
+    ///
+    /// * `batch` - Most identities to delete in a single query
+    pub fn purge_batch_size(mut self, batch: usize) -> SqlIdentityBuilder {
+        self.purge_batch = batch;
+        self
+    }
+
+    /// Set a function to call with the number of identities deleted by
+    /// each purge (e.g., to record metrics)
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - Function to call after each purge
+    pub fn on_purge<F>(mut self, callback: F) -> SqlIdentityBuilder
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.on_purge = Some(Arc::new(callback));
+        self
+    }
+
+    /// Bind sessions to the client they are used from.  Every request is
+    /// compared against the client the session was last used from, and
+    /// handled according to the binding policy if any binding does not
+    /// match.  By default, sessions are not bound
+    ///
+    /// # Arguments
+    ///
+    /// * `bindings` - Properties of the client to bind sessions to
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # extern crate actix_web_sql_identity;
+    /// use actix_web_sql_identity::{Binding, SqlIdentityBuilder};
+    ///
+    /// // Allow clients to move within a /24 (IPv4) or /64 (IPv6) network
+    /// let policy = SqlIdentityBuilder::new("sqlite://my.db")
+    ///                 .bind_sessions(vec![
+    ///                     Binding::Subnet { v4: 24, v6: 64 },
+    ///                     Binding::UserAgent,
+    ///                 ]);
+    /// ```
+    pub fn bind_sessions<I>(mut self, bindings: I) -> SqlIdentityBuilder
+    where
+        I: IntoIterator<Item = Binding>,
+    {
+        self.bindings = bindings.into_iter().collect();
+        self
+    }
+
+    /// Change what happens when a request does not match the client its
+    /// session is bound to (default: `BindingPolicy::Reject`)
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - Binding policy to use
+    pub fn binding_policy(mut self, policy: BindingPolicy) -> SqlIdentityBuilder {
+        self.binding_policy = policy;
+        self
+    }
+
+    /// Set the networks of the proxies (e.g., load balancers) allowed to
+    /// report the client address in the `Forwarded` or `X-Forwarded-For`
+    /// headers.  Those headers are ignored unless the request comes from
+    /// one of these networks, as any client may set them.  By default, no
+    /// proxies are trusted and the connected peer is used as the client
+    ///
+    /// # Arguments
+    ///
+    /// * `proxies` - Networks in CIDR notation (e.g., `10.0.0.0/8`, `::1`)
+    pub fn trusted_proxies<I>(mut self, proxies: I) -> SqlIdentityBuilder
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.trusted_proxies = proxies.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set a function that builds the response for an error, instead of
+    /// the default response (e.g., to add a `WWW-Authenticate` header).
+    /// Returning None uses the default response.  Requests made with an
+    /// invalid token (not found, expired, revoked or bound to another
+    /// client) are handled as anonymous, unless this function returns a
+    /// response for them
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - Function to build error responses with
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # extern crate actix_web;
+    /// # extern crate actix_web_sql_identity;
+    /// use actix_web::HttpResponse;
+    /// use actix_web_sql_identity::{SqlIdentityBuilder, SqlIdentityError};
+    ///
+    /// let policy = SqlIdentityBuilder::new("sqlite://my.db")
+    ///                 .error_handler(|e| match *e {
+    ///                     SqlIdentityError::TokenExpired => Some(
+    ///                         HttpResponse::Unauthorized()
+    ///                             .header("WWW-Authenticate", "Bearer error=\"invalid_token\"")
+    ///                             .finish(),
+    ///                     ),
+    ///                     _ => None,
+    ///                 });
+    /// ```
+    pub fn error_handler<F>(mut self, handler: F) -> SqlIdentityBuilder
+    where
+        F: Fn(&SqlIdentityError) -> Option<HttpResponse> + Send + Sync + 'static,
+    {
+        self.on_error = Some(Arc::new(handler));
+        self
+    }
+
+    /// Change how requests are authenticated while the database is
+    /// unavailable (default: `FailurePolicy::FailOpen`).  With
+    /// `FailurePolicy::ServeCached`, each worker thread keeps the most
+    /// recently validated tokens in memory
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - Failure policy to use
+    pub fn failure_policy(mut self, policy: FailurePolicy) -> SqlIdentityBuilder {
+        self.failure = policy;
+        self
+    }
+
+    /// Keep recently validated tokens in memory, so requests made with them
+    /// skip the database lookup.  Each worker thread has its own cache;
+    /// tokens forgotten or revoked through a worker are dropped from its
+    /// cache right away, but other workers (and other servers) may keep
+    /// accepting them until they were validated longer than `ttl` ago.  The
+    /// TTL should be shorter than the idle timeout, if set.  Also sets the
+    /// capacity of the cache used by `FailurePolicy::ServeCached`.  By
+    /// default, tokens are not cached
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - Most tokens to keep per worker thread
+    /// * `ttl` - How long a validated token is accepted without a lookup
+    pub fn cache_tokens(mut self, capacity: usize, ttl: Duration) -> SqlIdentityBuilder {
+        self.cache = Some((capacity, ttl));
+        self
+    }
+
+    /// Only save the last used time of an identity once it is older than
+    /// the given interval, instead of on every request.  Changes to the
+    /// client address or user agent are always saved.  The idle timeout
+    /// (if set) is checked against the saved time, so the interval should
+    /// be much shorter.  By default, every request saves the identity
+    ///
+    /// # Arguments
+    ///
+    /// * `interval` - How often the last used time is saved
+    pub fn touch_interval(mut self, interval: Duration) -> SqlIdentityBuilder {
+        self.touch_interval = Some(interval);
+        self
+    }
+
+    /// Keep identities in a custom store, instead of the database given by
+    /// the connection string (which is then ignored).  The pool size sets
+    /// how many threads call the store
+    ///
+    /// # Arguments
+    ///
+    /// * `store` - Store to keep identities in
+    pub fn store<T: IdentityStore + 'static>(mut self, store: T) -> SqlIdentityBuilder {
+        self.store = Some(Arc::new(store));
+        self
+    }
+
+    /// Create the `identities` table when building the policy, or upgrade
+    /// it to the version this crate expects.  The versions applied are
+    /// recorded in an `identities_migrations` table (named after the table,
+    /// if renamed).  Migrations run once per connection string and table,
+    /// the first time a worker builds the policy, and servers sharing a
+    /// MySQL or PostgreSQL database take turns migrating it.
+    /// Ignored for in-memory and custom stores.  By default, the table must
+    /// be created by hand (see sql/)
+    ///
+    /// # Arguments
+    ///
+    /// * `migrate` - True to create or upgrade the table
+    pub fn run_migrations(mut self, migrate: bool) -> SqlIdentityBuilder {
+        self.migrate = migrate;
+        self
+    }
+
+    /// Check the `identities` table when building the policy (after
+    /// running migrations, if enabled), so `finish()` fails with
+    /// `SqlIdentityError::InvalidSchema` listing every missing column,
+    /// incompatible type or column too short to hold a token hash (44
+    /// characters), instead of requests failing later.  Ignored for
+    /// in-memory and custom stores.  By default, the table is not checked
+    ///
+    /// # Arguments
+    ///
+    /// * `validate` - True to check the table
+    pub fn validate_schema(mut self, validate: bool) -> SqlIdentityBuilder {
+        self.validate = validate;
+        self
+    }
+
+    /// Change the name of the table identities are stored in (default:
+    /// `identities`).  Migrations and schema validation use this table too.
+    /// Names must be plain identifiers (letters, digits and underscores).
+    /// Ignored for in-memory and custom stores
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the table
+    pub fn table_name<T: Into<String>>(mut self, name: T) -> SqlIdentityBuilder {
+        self.names.set_table(name.into());
+        self
+    }
+
+    /// Change the name of a column of the identities table (by default,
+    /// columns are named as in the README, e.g. `userid`).  Names must be
+    /// plain identifiers (letters, digits and underscores).  Ignored for
+    /// in-memory and custom stores
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - Column to rename
+    /// * `name` - Name of the column
+    pub fn column_name<T: Into<String>>(mut self, column: Column, name: T) -> SqlIdentityBuilder {
+        self.names.set_column(column, name.into());
+        self
+    }
+
     /// Finish building this SQL identity policy.  This will attempt
     /// to construct the a pool of connections to the database
     /// specified.  The type of database is determined by the uri set.
@@ -421,14 +1622,88 @@ impl SqlIdentityBuilder {
     pub fn finish(self) -> Result<SqlIdentityPolicy, Error> {
         info!("Registering identity provider: {:?}", self.variant);
 
-        Ok(SqlIdentityPolicy(Rc::new(SqlIdentityInner::new(
-            match self.variant {
-                Variant::Sqlite => SqlActor::sqlite(self.pool, &self.uri)?,
-                Variant::Mysql => SqlActor::mysql(self.pool, &self.uri)?,
-                Variant::Pg => SqlActor::pg(self.pool, &self.uri)?,
+        let trusted_proxies = self.trusted_proxies
+            .iter()
+            .map(|proxy| Cidr::parse(proxy).ok_or_else(|| SqlIdentityError::InvalidProxy(proxy.clone())))
+            .collect::<Result<_, _>>()?;
+
+        self.names.check()?;
+
+        let store: Arc<IdentityStore> = match self.store {
+            Some(ref store) => Arc::clone(store),
+            None => match self.variant {
+                Variant::Sqlite => self.migrated(DieselStore::sqlite(&self.uri)?)?,
+                Variant::Mysql => self.migrated(DieselStore::mysql(&self.uri)?)?,
+                Variant::Pg => self.migrated(DieselStore::pg(&self.uri)?)?,
+                Variant::Memory => MemoryStore::shared(&self.uri),
             },
-            self.hdr,
-        ))))
+        };
+
+        let mut inner = SqlIdentityInner::new(SqlActor::start(self.pool, store), self.hdr);
+
+        inner.max_age = self.max_age;
+        inner.idle_timeout = self.idle_timeout;
+        inner.delete_idle = self.delete_idle;
+        inner.legacy_tokens = self.legacy_tokens;
+        inner.token_format = self.token_format;
+        inner.rotate_every = self.rotate_every;
+        inner.rotation_grace = self.rotation_grace;
+        inner.max_sessions = self.max_sessions;
+        inner.eviction = self.eviction;
+        inner.binding_policy = self.binding_policy;
+        inner.on_error = self.on_error.clone();
+        inner.failure = self.failure;
+        inner.cache_ttl = self.cache.map(|(_, ttl)| ttl);
+        inner.touch_interval = self.touch_interval;
+
+        let capacity = match (self.cache, self.failure) {
+            (Some((capacity, _)), _) => Some(capacity),
+            (None, FailurePolicy::ServeCached(_)) => Some(DEFAULT_CACHE_CAPACITY),
+            _ => None,
+        };
+        inner.cache = capacity.map(|capacity| Rc::new(TokenCache::new(capacity)));
+
+        inner.sources = match self.sources {
+            Some(sources) => sources,
+            None => Some(TokenSource::Bearer)
+                .into_iter()
+                .chain(self.cookie.clone().map(TokenSource::Cookie))
+                .collect(),
+        };
+
+        if let Some(every) = self.purge_every {
+            // Workers building the same policy share one purge of its store
+            let key = match self.store {
+                Some(ref store) => format!("store {:p}", &**store),
+                None => format!("{} {}", self.names.table(), self.uri),
+            };
+
+            PurgeActor {
+                addr: inner.addr.clone(),
+                every: every.to_std()?,
+                batch: self.purge_batch,
+                idle_timeout: self.idle_timeout,
+                callback: self.on_purge.clone(),
+            }.start_once(key);
+        }
+
+        inner.bindings = self.bindings;
+        inner.trusted_proxies = trusted_proxies;
+
+        let refresh_hdr = self.refresh_hdr;
+        inner.refresh = self.refresh.map(|(access_ttl, refresh_ttl)| RefreshConfig {
+            access_ttl,
+            refresh_ttl,
+            hdr: refresh_hdr,
+        });
+
+        let cookie_opts = self.cookie_opts;
+        inner.cookie = self.cookie.map(|name| CookieConfig {
+            name,
+            ..cookie_opts
+        });
+
+        Ok(SqlIdentityPolicy(Rc::new(inner)))
     }
 
     /// Forces a SQLite identity policy to be created.  This function does
@@ -470,44 +1745,82 @@ impl<S> IdentityPolicy<S> for SqlIdentityPolicy {
     /// * `req` - The HTTP request recieved
     fn from_request(&self, req: &HttpRequest<S>) -> Self::Future {
         let inner = Rc::clone(&self.0);
-        let conn_ip = req.connection_info()
-            .remote()
-            .map_or("0.0.0.0", |s| s.split(":").nth(0).unwrap())
-            .to_owned();
+        let (conn_ip, ua) = self.0.client_info(req);
+        let stateless = req.drop_state();
 
-        let unk = HeaderValue::from_static("Unknown");
-        let ua = req.headers()
-            .get("user-agent")
-            .unwrap_or(&unk)
-            .to_str()
-            .unwrap_or("Unknown")
-            .to_owned();
+        Box::new(self.0.load(req).and_then(move |loaded| {
+            let checked = loaded.and_then(|ident| match ident {
+                Some(id) => inner
+                    .check_idle(id)
+                    .and_then(|id| inner.check_binding(id, &conn_ip, &ua))
+                    .map(Some),
+                None => Ok(None),
+            });
+
+            // Requests made with an invalid token are handled as anonymous,
+            // unless the error handler has a response for them.  The failure
+            // policy decides whether requests are refused while the database
+            // is unavailable
+            let ident = match checked {
+                Ok(ident) => ident,
+                Err(e) => match inner.on_error.as_ref().and_then(|handler| handler(&e)) {
+                    Some(resp) => return Err(InternalError::from_response(e, resp).into()),
+                    None => match e {
+                        SqlIdentityError::BackendUnavailable if inner.failure != FailurePolicy::FailOpen => {
+                            return Err(e.into())
+                        }
+                        _ => None,
+                    },
+                },
+            };
 
-        Box::new(self.0.load(req).map(move |ident| {
-            if let Some(id) = ident {
+            stateless.extensions_mut().insert(CurrentSession {
+                inner: Rc::clone(&inner),
+                id: ident.as_ref().map(|id| id.id),
+                userid: ident.as_ref().map(|id| id.userid.clone()),
+            });
+
+            Ok(if let Some(id) = ident {
+                let moved = id.ip.as_ref() != Some(&conn_ip) || id.useragent.as_ref() != Some(&ua);
 
                 SqlIdentity {
                     id: id.id,
                     identity: Some(id.userid),
                     token: Some(id.token),
+                    verifier: id.verifier,
+                    refresh: None,
+                    family: id.family,
+                    replaces: None,
                     ip: Some(conn_ip),
                     user_agent: Some(ua),
                     created: id.created,
+                    issued: id.rotated.unwrap_or(id.created),
+                    modified: id.modified,
+                    moved,
                     state: SqlIdentityState::Updated,
                     inner: inner,
                 }
             } else {
+                let now = Utc::now().naive_utc();
+
                 SqlIdentity {
                     id: -1,
                     identity: None,
                     token: None,
+                    verifier: None,
+                    refresh: None,
+                    family: None,
+                    replaces: None,
                     ip: Some(conn_ip),
                     user_agent: Some(ua),
-                    created: Utc::now().naive_utc(),
+                    created: now,
+                    issued: now,
+                    modified: now,
+                    moved: false,
                     state: SqlIdentityState::Unchanged,
                     inner: inner,
                 }
-            }
+            })
         }))
     }
 }
@@ -0,0 +1,367 @@
+//! In-Memory Identity Store
+//!
+//! Keeps identities in memory, with the same behaviour as the SQL backend.
+//! Identities are lost when the process exits, so this is only suitable for
+//! tests and single-node deployments that can afford to log everyone out
+//! on restart.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use chrono::prelude::Utc;
+use chrono::{Duration, NaiveDateTime};
+
+// Failure (error management system) Imports
+use failure::Error;
+
+use sql::{
+    CreateIdentity, CreateSession, RefreshIdentity, RotateIdentity, SqlIdentityModel,
+    UpdateIdentity, KIND_ACCESS, KIND_REFRESH,
+};
+use store::{Delete, IdentityStore};
+
+use super::{EvictionPolicy, SqlIdentityError};
+
+lazy_static! {
+    /// Stores opened by connection string (e.g., `memory://`), so every
+    /// worker thread of a server shares the same identities
+    static ref SHARED: Mutex<HashMap<String, Arc<MemoryStore>>> = Mutex::new(HashMap::new());
+}
+
+/// Identities, by id
+struct Rows {
+    next: i64,
+    rows: BTreeMap<i64, SqlIdentityModel>,
+}
+
+impl Rows {
+    /// Inserts a newly issued token, returning its id
+    ///
+    /// # Arguments
+    ///
+    /// * `new` - Token to insert
+    fn insert(&mut self, new: &CreateIdentity) -> i64 {
+        self.next += 1;
+        let id = self.next;
+
+        self.rows.insert(
+            id,
+            SqlIdentityModel {
+                id,
+                token: new.token.clone(),
+                userid: new.userid.clone(),
+                ip: new.ip.clone(),
+                useragent: new.useragent.clone(),
+                created: new.created,
+                modified: new.modified,
+                expires: new.expires,
+                verifier: new.verifier.clone(),
+                previous: None,
+                rotated: None,
+                kind: new.kind.clone(),
+                family: new.family.clone(),
+                revoked: None,
+            },
+        );
+
+        id
+    }
+
+    /// Deletes every identity matching a condition, returning the ids of
+    /// those deleted
+    ///
+    /// # Arguments
+    ///
+    /// * `condition` - Returns true for identities to delete
+    fn remove_where<F: Fn(&SqlIdentityModel) -> bool>(&mut self, condition: F) -> Vec<i64> {
+        let doomed: Vec<i64> = self
+            .rows
+            .values()
+            .filter(|row| condition(row))
+            .map(|row| row.id)
+            .collect();
+
+        for id in &doomed {
+            self.rows.remove(id);
+        }
+
+        doomed
+    }
+
+    /// Deletes an identity, along with every token in its family, returning
+    /// the ids of those deleted
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Id of the identity to delete
+    fn remove_with_family(&mut self, id: i64) -> Vec<i64> {
+        match self.rows.get(&id).map(|row| row.family.clone()) {
+            Some(Some(family)) => self.remove_where(|row| row.family.as_ref() == Some(&family)),
+            Some(None) => self.rows.remove(&id).map_or(vec![], |_| vec![id]),
+            None => vec![],
+        }
+    }
+}
+
+/// Identity store that keeps identities in memory
+pub struct MemoryStore(Mutex<Rows>);
+
+impl MemoryStore {
+    /// Creates an empty store
+    pub fn new() -> MemoryStore {
+        MemoryStore(Mutex::new(Rows {
+            next: 0,
+            rows: BTreeMap::new(),
+        }))
+    }
+
+    /// Returns the store opened with a connection string, creating it if
+    /// needed.  Stores with different connection strings (e.g.,
+    /// `memory://a` and `memory://b`) are kept apart
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - Connection string
+    pub(crate) fn shared(uri: &str) -> Arc<MemoryStore> {
+        let mut shared = SHARED.lock().unwrap_or_else(|e| e.into_inner());
+
+        Arc::clone(
+            shared
+                .entry(uri.to_string())
+                .or_insert_with(|| Arc::new(MemoryStore::new())),
+        )
+    }
+
+    /// Locks the identities, for the duration of a single operation
+    fn rows<'a>(&'a self) -> MutexGuard<'a, Rows> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> MemoryStore {
+        MemoryStore::new()
+    }
+}
+
+impl IdentityStore for MemoryStore {
+    fn find(&self, tokens: &[String], kind: &str) -> Result<Option<SqlIdentityModel>, Error> {
+        let rows = self.rows();
+
+        Ok(rows
+            .rows
+            .values()
+            .find(|row| {
+                row.kind == kind
+                    && (tokens.contains(&row.token)
+                        || row.previous.as_ref().map_or(false, |previous| tokens.contains(previous)))
+            })
+            .cloned())
+    }
+
+    fn create(&self, session: &CreateSession) -> Result<Vec<i64>, Error> {
+        let mut rows = self.rows();
+
+        if let Some(replaces) = session.replaces {
+            rows.remove_with_family(replaces);
+        }
+
+        let mut evicted = Vec::new();
+
+        if let Some(max) = session.max_sessions {
+            // A session is a family of tokens (or a single access token),
+            // live while any of its tokens is, and last used when the most
+            // recently used of them was
+            let now = Utc::now().naive_utc();
+            let mut families: HashMap<(Option<String>, Option<i64>), (i64, NaiveDateTime)> =
+                HashMap::new();
+
+            for row in rows.rows.values().filter(|row| {
+                row.userid == session.access.userid
+                    && row.revoked.is_none()
+                    && row.expires.map_or(true, |exp| exp > now)
+            }) {
+                let key = (row.family.clone(), if row.family.is_none() { Some(row.id) } else { None });
+                let entry = families.entry(key).or_insert((row.id, row.modified));
+                entry.0 = entry.0.min(row.id);
+                entry.1 = entry.1.max(row.modified);
+            }
+
+            let mut active: Vec<(i64, NaiveDateTime)> = families.values().cloned().collect();
+            let excess = active.len() as i64 + 1 - max as i64;
+
+            if excess > 0 {
+                if session.eviction == EvictionPolicy::Reject {
+                    return Err(SqlIdentityError::TooManySessions.into());
+                }
+
+                // Evict the least recently used sessions, along with any
+                // tokens in their families
+                active.sort_by_key(|&(_, modified)| modified);
+
+                for &(victim, _) in active.iter().take(excess as usize) {
+                    info!("Evicting identity {} of {}", victim, session.access.userid);
+                    evicted.extend(rows.remove_with_family(victim));
+                }
+            }
+        }
+
+        rows.insert(&session.access);
+        if let Some(ref refresh) = session.refresh {
+            rows.insert(refresh);
+        }
+
+        Ok(evicted)
+    }
+
+    fn touch(&self, update: &UpdateIdentity) -> Result<usize, Error> {
+        let mut rows = self.rows();
+
+        match rows.rows.get_mut(&update.id) {
+            Some(row) => {
+                row.ip = update.ip.clone();
+                row.useragent = update.useragent.clone();
+                row.modified = update.modified;
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn rotate(&self, rotate: &RotateIdentity) -> Result<usize, Error> {
+        let mut rows = self.rows();
+        let current_verifier = rotate.current_verifier.as_ref().map_or("", |s| s.as_ref());
+
+        match rows.rows.get_mut(&rotate.id) {
+            Some(ref mut row)
+                if row.token == rotate.current
+                    && row.verifier.as_ref().map_or("", |s| s.as_ref()) == current_verifier =>
+            {
+                let changes = &rotate.changes;
+                row.token = changes.token.clone();
+                row.verifier = changes.verifier.clone();
+                row.previous = changes.previous.clone();
+                row.rotated = Some(changes.rotated);
+                row.ip = changes.ip.clone();
+                row.useragent = changes.useragent.clone();
+                row.modified = changes.modified;
+                Ok(1)
+            }
+            _ => Ok(0),
+        }
+    }
+
+    fn refresh(&self, msg: &RefreshIdentity) -> Result<Option<String>, Error> {
+        let mut rows = self.rows();
+        let now = Utc::now().naive_utc();
+
+        let session = match rows
+            .rows
+            .values()
+            .find(|row| row.kind == KIND_REFRESH && msg.lookup.tokens.contains(&row.token))
+        {
+            Some(session) => session.clone(),
+            None => return Err(SqlIdentityError::TokenNotFound.into()),
+        };
+
+        if !msg.lookup.matches(&session, Duration::zero()) {
+            return Err(SqlIdentityError::TokenNotFound.into());
+        }
+
+        let fam = session.family.clone().unwrap_or_else(|| session.token.clone());
+
+        // A used refresh token presented again revokes its whole family
+        if session.revoked.is_some() {
+            rows.remove_where(|row| row.family.as_ref() == Some(&fam));
+            return Ok(None);
+        }
+
+        if session.expires.map_or(false, |exp| exp <= now) {
+            return Err(SqlIdentityError::TokenExpired.into());
+        }
+
+        // Retire the refresh token (kept to detect reuse) and the access
+        // tokens issued alongside it
+        if let Some(row) = rows.rows.get_mut(&session.id) {
+            row.revoked = Some(now);
+        }
+
+        rows.remove_where(|row| row.family.as_ref() == Some(&fam) && row.kind == KIND_ACCESS);
+
+        for new in &[&msg.access, &msg.refresh] {
+            rows.insert(&CreateIdentity {
+                userid: session.userid.clone(),
+                family: Some(fam.clone()),
+                ..(*new).clone()
+            });
+        }
+
+        Ok(Some(session.userid))
+    }
+
+    fn delete(&self, target: &Delete) -> Result<usize, Error> {
+        let mut rows = self.rows();
+
+        let deleted = match *target {
+            Delete::Token(ref stored) => rows.remove_where(|row| &row.token == stored),
+
+            Delete::Family(ref fam) => rows.remove_where(|row| row.family.as_ref() == Some(fam)),
+
+            Delete::Session { ref userid, id } => {
+                match rows.rows.get(&id) {
+                    Some(row) if &row.userid == userid => (),
+                    _ => return Ok(0),
+                }
+
+                rows.remove_with_family(id)
+            }
+
+            Delete::User { ref userid, keep } => {
+                let keep_family = keep
+                    .and_then(|keep| rows.rows.get(&keep))
+                    .and_then(|row| row.family.clone());
+
+                rows.remove_where(|row| {
+                    &row.userid == userid
+                        && Some(row.id) != keep
+                        && (keep_family.is_none() || row.family != keep_family)
+                })
+            }
+
+            Delete::Expired { batch, idle_timeout } => {
+                let now = Utc::now().naive_utc();
+                let cutoff = idle_timeout.map(|timeout| now - timeout);
+
+                let ids: Vec<i64> = rows
+                    .rows
+                    .values()
+                    .filter(|row| {
+                        row.expires.map_or(false, |exp| exp <= now)
+                            || cutoff.map_or(false, |cutoff| row.kind == KIND_ACCESS && row.modified <= cutoff)
+                    })
+                    .map(|row| row.id)
+                    .take(batch)
+                    .collect();
+
+                rows.remove_where(|row| ids.contains(&row.id))
+            }
+        };
+
+        Ok(deleted.len())
+    }
+
+    fn list(&self, userid: &str) -> Result<Vec<SqlIdentityModel>, Error> {
+        let rows = self.rows();
+
+        let mut sessions: Vec<SqlIdentityModel> = rows
+            .rows
+            .values()
+            .filter(|row| row.userid == userid && row.kind == KIND_ACCESS && row.revoked.is_none())
+            .cloned()
+            .collect();
+
+        sessions.sort_by_key(|session| Reverse(session.modified));
+        Ok(sessions)
+    }
+}
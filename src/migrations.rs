@@ -0,0 +1,249 @@
+//! Schema Migrations
+//!
+//! Creates or upgrades the `identities` table, one version at a time.  Each
+//! version applied is recorded in the `identities_migrations` table.  Tables
+//! created before then (e.g., by hand from the files in sql/) are upgraded
+//! from the last version whose columns they already have.  Migrations are
+//! written with the default table and column names, and rendered with the
+//! configured ones.  Servers sharing a database take a lock on it (MySQL and
+//! PostgreSQL) while migrating, so only one of them applies each version.
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+// Diesel (SQL ORM) Imports
+use diesel::deserialize::FromSql;
+use diesel::sql_types::Integer;
+use diesel::{self, Connection, RunQueryDsl};
+
+// Failure (error management system) Imports
+use failure::Error;
+
+use super::SqlIdentityError;
+use names::Names;
+
+lazy_static! {
+    /// Connection strings already migrated by this process, so the workers
+    /// of a server only migrate once (and never at the same time)
+    static ref MIGRATED: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// A single version of the schema
+pub(crate) struct Migration {
+    /// Version number, starting at 1
+    pub version: i32,
+
+    /// Name of the migration (and of its file in sql/migrations)
+    pub name: &'static str,
+
+    /// A column added by this version, used to detect tables that were
+    /// created without recording their version
+    pub column: &'static str,
+
+    /// Statements to run
+    pub sql: &'static str,
+}
+
+/// Every migration of a SQL variant
+pub(crate) struct Migrations {
+    /// Creates the table recording the versions applied, if needed
+    pub create: &'static str,
+
+    /// Migrations, in order
+    pub steps: &'static [Migration],
+}
+
+/// Embeds the migrations of a SQL variant, from sql/migrations/<dir>
+macro_rules! migrations {
+    ($dir:expr, $create:expr, [$(($version:expr, $name:expr, $column:expr)),*]) => {
+        Migrations {
+            create: $create,
+            steps: &[$(Migration {
+                version: $version,
+                name: $name,
+                column: $column,
+                sql: include_str!(concat!("../sql/migrations/", $dir, "/", $name, ".sql")),
+            }),*],
+        }
+    };
+}
+
+#[cfg(feature = "sqlite")]
+pub(crate) const SQLITE: Migrations = migrations!(
+    "sqlite",
+    "CREATE TABLE IF NOT EXISTS identities_migrations (
+        version INTEGER PRIMARY KEY NOT NULL,
+        applied DATETIME NOT NULL
+    )",
+    [
+        (1, "001_create_identities", "token"),
+        (2, "002_add_expires", "expires"),
+        (3, "003_add_verifier", "verifier"),
+        (4, "004_add_rotation", "rotated"),
+        (5, "005_add_refresh_tokens", "kind")
+    ]
+);
+
+#[cfg(feature = "mysql")]
+pub(crate) const MYSQL: Migrations = migrations!(
+    "mysql",
+    "CREATE TABLE IF NOT EXISTS identities_migrations (
+        version INTEGER PRIMARY KEY NOT NULL,
+        applied DATETIME NOT NULL
+    )",
+    [
+        (1, "001_create_identities", "token"),
+        (2, "002_add_expires", "expires"),
+        (3, "003_add_verifier", "verifier"),
+        (4, "004_add_rotation", "rotated"),
+        (5, "005_add_refresh_tokens", "kind")
+    ]
+);
+
+#[cfg(feature = "postgres")]
+pub(crate) const POSTGRES: Migrations = migrations!(
+    "postgres",
+    "CREATE TABLE IF NOT EXISTS identities_migrations (
+        version INTEGER PRIMARY KEY NOT NULL,
+        applied timestamp NOT NULL
+    )",
+    [
+        (1, "001_create_identities", "token"),
+        (2, "002_add_expires", "expires"),
+        (3, "003_add_verifier", "verifier"),
+        (4, "004_add_rotation", "rotated"),
+        (5, "005_add_refresh_tokens", "kind")
+    ]
+);
+
+/// A version recorded in the `identities_migrations` table
+#[derive(QueryableByName)]
+struct Applied {
+    #[sql_type = "Integer"]
+    version: i32,
+}
+
+/// Runs a migration once per connection string and table for this
+/// process.  Later calls for the same table do nothing
+///
+/// # Arguments
+///
+/// * `uri` - Connection string of the database
+/// * `names` - Names of the table and its columns
+/// * `migrate` - Migrates the database
+pub(crate) fn once<F>(uri: &str, names: &Names, migrate: F) -> Result<(), Error>
+where
+    F: FnOnce() -> Result<i32, Error>,
+{
+    let mut migrated = MIGRATED.lock().unwrap_or_else(|e| e.into_inner());
+    let key = format!("{} {}", names.table(), uri);
+
+    if !migrated.contains(&key) {
+        let version = migrate()?;
+        info!("Identity schema is at version {}", version);
+        migrated.insert(key);
+    }
+
+    Ok(())
+}
+
+/// Applies every migration newer than the version of the database, each in
+/// its own transaction.  MySQL commits schema changes right away, so each of
+/// its migrations is a single statement, which either applies entirely or
+/// not at all; a migration whose column already exists (e.g., applied by a
+/// process that failed to record it) is only recorded.  Callers sharing a
+/// database should hold a lock across this.  Returns the version the
+/// database is now at
+///
+/// # Arguments
+///
+/// * `conn` - Connection to the database
+/// * `migrations` - Migrations of the SQL variant of the database
+/// * `names` - Names of the table and its columns
+pub(crate) fn run<C>(conn: &C, migrations: &Migrations, names: &Names) -> Result<i32, Error>
+where
+    C: Connection,
+    i32: FromSql<Integer, C::Backend>,
+{
+    conn.batch_execute(&names.sql(migrations.create))?;
+
+    let mut current = diesel::sql_query(names.sql("SELECT version FROM identities_migrations"))
+        .load::<Applied>(conn)?
+        .iter()
+        .map(|applied| applied.version)
+        .max()
+        .unwrap_or(0);
+
+    if current == 0 {
+        current = detect(conn, migrations, names);
+
+        for migration in migrations.steps.iter().take_while(|m| m.version <= current) {
+            record(conn, migration, names)?;
+        }
+    }
+
+    let pending: Vec<&Migration> = migrations.steps.iter().filter(|m| m.version > current).collect();
+
+    for migration in pending {
+        if has_column(conn, migration, names) {
+            info!("Identity migration {} was already applied", migration.name);
+            record(conn, migration, names)?;
+        } else {
+            info!("Applying identity migration {}", migration.name);
+
+            conn.transaction::<_, Error, _>(|| {
+                conn.batch_execute(&names.sql(migration.sql))?;
+                record(conn, migration, names)
+            }).map_err(|e| SqlIdentityError::MigrationFailed(format!("{}: {}", migration.name, e)))?;
+        }
+
+        current = migration.version;
+    }
+
+    Ok(current)
+}
+
+/// Returns the version of an `identities` table that has no versions
+/// recorded, from the columns it has (0 if there is no table)
+///
+/// # Arguments
+///
+/// * `conn` - Connection to the database
+/// * `migrations` - Migrations of the SQL variant of the database
+/// * `names` - Names of the table and its columns
+fn detect<C: Connection>(conn: &C, migrations: &Migrations, names: &Names) -> i32 {
+    migrations
+        .steps
+        .iter()
+        .take_while(|migration| has_column(conn, migration, names))
+        .last()
+        .map_or(0, |migration| migration.version)
+}
+
+/// Checks if the column added by a migration exists
+///
+/// # Arguments
+///
+/// * `conn` - Connection to the database
+/// * `migration` - Migration adding the column
+/// * `names` - Names of the table and its columns
+fn has_column<C: Connection>(conn: &C, migration: &Migration, names: &Names) -> bool {
+    let probe = format!("SELECT {} FROM identities WHERE 1 = 0", migration.column);
+    diesel::sql_query(names.sql(&probe)).execute(conn).is_ok()
+}
+
+/// Records that a migration was applied
+///
+/// # Arguments
+///
+/// * `conn` - Connection to the database
+/// * `migration` - Migration applied
+/// * `names` - Names of the table and its columns
+fn record<C: Connection>(conn: &C, migration: &Migration, names: &Names) -> Result<(), Error> {
+    diesel::sql_query(names.sql(&format!(
+        "INSERT INTO identities_migrations (version, applied) VALUES ({}, CURRENT_TIMESTAMP)",
+        migration.version
+    ))).execute(conn)?;
+
+    Ok(())
+}
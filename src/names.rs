@@ -0,0 +1,215 @@
+//! Table and Column Names
+//!
+//! Identities are stored in the `identities` table by default, but the table
+//! and any of its columns may be renamed (see `SqlIdentityBuilder::table_name`
+//! and `SqlIdentityBuilder::column_name`).  Queries are written with the
+//! default names, then rendered with the configured ones.
+
+use std::collections::HashMap;
+
+use super::SqlIdentityError;
+
+/// Default name of the table identities are stored in
+const DEFAULT_TABLE: &'static str = "identities";
+
+/// A column of the identities table
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Column {
+    Id,
+    Token,
+    UserId,
+    Ip,
+    UserAgent,
+    Created,
+    Modified,
+    Expires,
+    Verifier,
+    Previous,
+    Rotated,
+    Kind,
+    Family,
+    Revoked,
+}
+
+impl Column {
+    /// Every column, in the order they are selected
+    pub(crate) const ALL: &'static [Column] = &[
+        Column::Id,
+        Column::Token,
+        Column::UserId,
+        Column::Ip,
+        Column::UserAgent,
+        Column::Created,
+        Column::Modified,
+        Column::Expires,
+        Column::Verifier,
+        Column::Previous,
+        Column::Rotated,
+        Column::Kind,
+        Column::Family,
+        Column::Revoked,
+    ];
+
+    /// Returns the default name of the column (e.g., `userid`)
+    pub fn default_name(&self) -> &'static str {
+        match *self {
+            Column::Id => "id",
+            Column::Token => "token",
+            Column::UserId => "userid",
+            Column::Ip => "ip",
+            Column::UserAgent => "useragent",
+            Column::Created => "created",
+            Column::Modified => "modified",
+            Column::Expires => "expires",
+            Column::Verifier => "verifier",
+            Column::Previous => "previous",
+            Column::Rotated => "rotated",
+            Column::Kind => "kind",
+            Column::Family => "family",
+            Column::Revoked => "revoked",
+        }
+    }
+}
+
+/// Names of the identities table and its columns
+#[derive(Clone, Debug)]
+pub(crate) struct Names {
+    table: Option<String>,
+    columns: HashMap<Column, String>,
+}
+
+impl Names {
+    /// Creates the default names
+    pub fn new() -> Names {
+        Names {
+            table: None,
+            columns: HashMap::new(),
+        }
+    }
+
+    /// Renames the table
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the table
+    pub fn set_table(&mut self, name: String) {
+        self.table = Some(name);
+    }
+
+    /// Renames a column
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - Column to rename
+    /// * `name` - Name of the column
+    pub fn set_column(&mut self, column: Column, name: String) {
+        self.columns.insert(column, name);
+    }
+
+    /// Returns the name of the table
+    pub fn table(&self) -> &str {
+        self.table.as_ref().map_or(DEFAULT_TABLE, |s| s.as_ref())
+    }
+
+    /// Returns the name of a column
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - Column to name
+    pub fn column(&self, column: Column) -> &str {
+        self.columns
+            .get(&column)
+            .map_or(column.default_name(), |s| s.as_ref())
+    }
+
+    /// Checks that every name is a plain identifier (letters, digits and
+    /// underscores, not starting with a digit), so it can be used in
+    /// queries as-is
+    pub fn check(&self) -> Result<(), SqlIdentityError> {
+        let names = Some(self.table()).into_iter().chain(self.columns.values().map(|s| s.as_ref()));
+
+        for name in names {
+            let plain = name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                && name.chars().next().map_or(false, |c| !c.is_ascii_digit());
+
+            if !plain {
+                return Err(SqlIdentityError::InvalidName(name.to_string()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the select list for a whole identity, with each column
+    /// aliased to its default name
+    pub fn select(&self) -> String {
+        Column::ALL
+            .iter()
+            .map(|&column| format!("{} AS {}", self.column(column), column.default_name()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Renders a statement written with the default names, replacing the
+    /// table (and names derived from it, e.g., `identities_migrations` or
+    /// `identities_previous`) and every column with their configured names.
+    /// String literals and aliases (e.g., `family` in `family AS family`)
+    /// are left as they are, so rows can be read by their default column
+    /// names
+    ///
+    /// # Arguments
+    ///
+    /// * `sql` - Statement written with the default names
+    pub fn sql(&self, sql: &str) -> String {
+        let mut rendered = String::with_capacity(sql.len());
+        let mut word = String::new();
+        let mut alias = false;
+        let mut quoted = false;
+
+        for c in sql.chars().chain(Some(' ')) {
+            if !quoted && (c.is_ascii_alphanumeric() || c == '_') {
+                word.push(c);
+                continue;
+            }
+
+            if !word.is_empty() {
+                if alias {
+                    rendered.push_str(&word);
+                } else {
+                    rendered.push_str(&self.rename(&word));
+                }
+
+                alias = word.eq_ignore_ascii_case("AS");
+                word.clear();
+            }
+
+            if c == '\'' {
+                quoted = !quoted;
+            }
+
+            rendered.push(c);
+        }
+
+        rendered.pop();
+        rendered
+    }
+
+    /// Returns the configured name of an identifier written with its
+    /// default name, or the identifier itself if it names nothing
+    ///
+    /// # Arguments
+    ///
+    /// * `word` - Identifier (or keyword) to rename
+    fn rename(&self, word: &str) -> String {
+        if word == DEFAULT_TABLE {
+            return self.table().to_string();
+        } else if word.starts_with("identities_") {
+            return format!("{}{}", self.table(), &word[DEFAULT_TABLE.len()..]);
+        }
+
+        match Column::ALL.iter().find(|column| column.default_name() == word) {
+            Some(&column) => self.column(column).to_string(),
+            None => word.to_string(),
+        }
+    }
+}
@@ -0,0 +1,100 @@
+//! Expired Session Purging
+//!
+//! Periodically deletes expired (and idle) identities from the database,
+//! so stale rows do not accumulate.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration as StdDuration;
+
+// Actix Imports
+use actix::prelude::{Actor, AsyncContext, Context, WrapFuture};
+use actix::{fut, ActorFuture, Addr};
+
+use chrono::Duration;
+
+use sql::SqlActor;
+use store::Delete;
+
+/// Function called with the number of identities removed by each purge
+pub(crate) type PurgeCallback = Arc<Fn(usize) + Send + Sync>;
+
+lazy_static! {
+    /// Purges running in this process, by the store they purge, so the
+    /// workers of a server share a single purge
+    static ref STARTED: Mutex<HashMap<String, Addr<PurgeActor>>> = Mutex::new(HashMap::new());
+}
+
+/// Actor that purges expired identities on an interval.  Rows are deleted
+/// in bounded batches, so the table is never locked for long
+pub(crate) struct PurgeActor {
+    pub addr: Addr<SqlActor>,
+    pub every: StdDuration,
+    pub batch: usize,
+    pub idle_timeout: Option<Duration>,
+    pub callback: Option<PurgeCallback>,
+}
+
+impl PurgeActor {
+    /// Starts purging a store, unless this process is already purging it
+    /// (e.g., from another worker).  A purge whose worker has stopped is
+    /// replaced
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - Identifies the store (e.g., its table and connection string)
+    pub fn start_once(self, key: String) {
+        let mut started = STARTED.lock().unwrap_or_else(|e| e.into_inner());
+
+        if started.get(&key).map_or(false, |addr| addr.connected()) {
+            return;
+        }
+
+        started.insert(key, self.start());
+    }
+
+    /// Deletes the next batch of expired identities, continuing with
+    /// another batch until a partial one is deleted
+    ///
+    /// # Arguments
+    ///
+    /// * `total` - Identities deleted by earlier batches of this purge
+    fn purge(&mut self, ctx: &mut Context<Self>, total: usize) {
+        let msg = Delete::Expired {
+            batch: self.batch,
+            idle_timeout: self.idle_timeout,
+        };
+
+        ctx.spawn(self.addr.send(msg).into_actor(self).then(move |res, act, ctx| {
+            match res {
+                Ok(Ok(n)) if n > 0 && n >= act.batch => act.purge(ctx, total + n),
+                Ok(Ok(n)) => act.report(total + n),
+                Ok(Err(e)) => error!("Failed to purge expired identities: {:?}", e),
+                Err(e) => error!("Failed to purge expired identities: {:?}", e),
+            }
+
+            fut::ok(())
+        }));
+    }
+
+    /// Reports how many identities a purge deleted
+    ///
+    /// # Arguments
+    ///
+    /// * `total` - Identities deleted
+    fn report(&self, total: usize) {
+        info!("Purged {} expired identities", total);
+
+        if let Some(ref callback) = self.callback {
+            callback(total);
+        }
+    }
+}
+
+impl Actor for PurgeActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.every, |act, ctx| act.purge(ctx, 0));
+    }
+}
@@ -0,0 +1,185 @@
+//! Dynamic Queries
+//!
+//! Statements built at runtime (e.g., with configured table and column
+//! names), written with `?` placeholders.  Each placeholder is sent as a
+//! bind parameter, in the syntax of the backend it runs on (e.g., `$1` for
+//! PostgreSQL), so values are never formatted into the statement.
+
+use chrono::NaiveDateTime;
+
+// Diesel (SQL ORM) Imports
+use diesel::backend::Backend;
+use diesel::query_builder::{AstPass, QueryFragment, QueryId};
+use diesel::query_source::QueryableByName;
+use diesel::result::{Error, QueryResult};
+use diesel::serialize::ToSql;
+use diesel::sql_types::{BigInt, HasSqlType, Nullable, Text, Timestamp};
+use diesel::Connection;
+
+/// A value bound to a placeholder
+#[derive(Debug)]
+pub(crate) enum Value {
+    BigInt(i64),
+    Text(Option<String>),
+    Timestamp(Option<NaiveDateTime>),
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Value {
+        Value::BigInt(value)
+    }
+}
+
+impl<'a> From<&'a i64> for Value {
+    fn from(value: &'a i64) -> Value {
+        Value::BigInt(*value)
+    }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(value: &'a str) -> Value {
+        Value::Text(Some(value.to_string()))
+    }
+}
+
+impl<'a> From<&'a String> for Value {
+    fn from(value: &'a String) -> Value {
+        Value::Text(Some(value.clone()))
+    }
+}
+
+impl<'a> From<&'a Option<String>> for Value {
+    fn from(value: &'a Option<String>) -> Value {
+        Value::Text(value.clone())
+    }
+}
+
+impl From<NaiveDateTime> for Value {
+    fn from(value: NaiveDateTime) -> Value {
+        Value::Timestamp(Some(value))
+    }
+}
+
+impl From<Option<NaiveDateTime>> for Value {
+    fn from(value: Option<NaiveDateTime>) -> Value {
+        Value::Timestamp(value)
+    }
+}
+
+/// A statement and the values bound to its placeholders, in order
+#[derive(Debug)]
+pub(crate) struct Query {
+    sql: String,
+    binds: Vec<Value>,
+}
+
+impl Query {
+    /// Creates a statement, without any values bound yet
+    ///
+    /// # Arguments
+    ///
+    /// * `sql` - Statement, with a `?` for each value
+    pub fn new<S: Into<String>>(sql: S) -> Query {
+        Query {
+            sql: sql.into(),
+            binds: Vec::new(),
+        }
+    }
+
+    /// Binds a value to the next placeholder
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - Value to bind
+    pub fn bind<V: Into<Value>>(mut self, value: V) -> Query {
+        self.binds.push(value.into());
+        self
+    }
+
+    /// Binds several values to the next placeholders (e.g., those returned
+    /// by `placeholders`)
+    ///
+    /// # Arguments
+    ///
+    /// * `values` - Values to bind
+    pub fn bind_all<'a, V>(mut self, values: &'a [V]) -> Query
+    where
+        &'a V: Into<Value>,
+    {
+        self.binds.extend(values.iter().map(Into::into));
+        self
+    }
+
+    /// Runs the statement, returning how many rows were changed
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - Connection to run the statement on
+    pub fn execute<C>(&self, conn: &C) -> QueryResult<usize>
+    where
+        C: Connection,
+        Query: QueryFragment<C::Backend>,
+    {
+        conn.execute_returning_count(self)
+    }
+
+    /// Runs the statement, returning the rows it selected (matched by
+    /// column name)
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - Connection to run the statement on
+    pub fn load<C, U>(&self, conn: &C) -> QueryResult<Vec<U>>
+    where
+        C: Connection,
+        Query: QueryFragment<C::Backend>,
+        U: QueryableByName<C::Backend>,
+    {
+        conn.query_by_name(self)
+    }
+}
+
+/// Returns a placeholder for each of several values, separated by commas
+/// (e.g., for an `IN` list)
+///
+/// # Arguments
+///
+/// * `n` - Number of values
+pub(crate) fn placeholders(n: usize) -> String {
+    vec!["?"; n].join(", ")
+}
+
+impl QueryId for Query {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<DB> QueryFragment<DB> for Query
+where
+    DB: Backend + HasSqlType<BigInt> + HasSqlType<Text> + HasSqlType<Timestamp>,
+    i64: ToSql<BigInt, DB>,
+    String: ToSql<Text, DB>,
+    NaiveDateTime: ToSql<Timestamp, DB>,
+{
+    fn walk_ast(&self, mut out: AstPass<DB>) -> QueryResult<()> {
+        let mut binds = self.binds.iter();
+
+        for (i, sql) in self.sql.split('?').enumerate() {
+            if i > 0 {
+                match binds.next() {
+                    Some(&Value::BigInt(ref value)) => out.push_bind_param::<BigInt, _>(value)?,
+                    Some(&Value::Text(ref value)) => out.push_bind_param::<Nullable<Text>, _>(value)?,
+                    Some(&Value::Timestamp(ref value)) => {
+                        out.push_bind_param::<Nullable<Timestamp>, _>(value)?
+                    }
+                    None => return Err(Error::QueryBuilderError("missing bind parameter".into())),
+                }
+            }
+
+            out.push_sql(sql);
+        }
+
+        Ok(())
+    }
+}
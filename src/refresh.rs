@@ -0,0 +1,199 @@
+//! Refresh tokens
+//!
+//! When enabled, remembering an identity issues a short-lived access token
+//! (used to authenticate requests) and a long-lived refresh token, which is
+//! only accepted by the `RefreshHandler` to obtain a new pair.
+
+use std::rc::Rc;
+
+use chrono::Duration;
+
+// Actix Web imports
+use actix_web::dev::Handler;
+use actix_web::error::Error as ActixWebError;
+use actix_web::http::header::HeaderValue;
+use actix_web::http::StatusCode;
+use actix_web::{FutureResponse, HttpRequest, HttpResponse};
+
+// Futures imports
+use futures::future::err as FutErr;
+use futures::Future;
+
+use sql::{
+    CreateIdentity, CreateSession, RefreshIdentity, UpdateIdentity, KIND_ACCESS, KIND_REFRESH,
+};
+use token::{self, TokenSource};
+
+use super::{SqlIdentity, SqlIdentityError, SqlIdentityInner};
+
+/// Settings for issuing access and refresh token pairs
+pub(crate) struct RefreshConfig {
+    pub access_ttl: Duration,
+    pub refresh_ttl: Duration,
+    pub hdr: &'static str,
+}
+
+impl RefreshConfig {
+    /// Places a newly issued refresh token in the refresh response header
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - Policy issuing the token, to respond to failures with
+    /// * `token` - Raw refresh token to hand to the client
+    /// * `resp` - HTTP response to modify
+    pub fn set_token(
+        &self,
+        inner: &SqlIdentityInner,
+        token: &str,
+        resp: &mut HttpResponse,
+    ) -> Result<(), ActixWebError> {
+        match token.parse::<HeaderValue>() {
+            Ok(value) => {
+                resp.headers_mut().append(self.hdr, value);
+                Ok(())
+            }
+            Err(_) => {
+                error!("Failed to parse refresh token to place in header!");
+                Err(inner.fail(SqlIdentityError::TokenNotSet))
+            }
+        }
+    }
+
+    /// Prepares the access and refresh tokens of a newly remembered
+    /// identity, as the first members of a new family
+    ///
+    /// # Arguments
+    ///
+    /// * `identity` - Identity being remembered (holding the access token)
+    /// * `refresh` - Raw refresh token to hand to the client
+    pub fn pair(&self, identity: &SqlIdentity, refresh: &str) -> CreateSession {
+        let family = Some(token::generate());
+
+        let mut session = UpdateIdentity::session(identity);
+        session.access.expires = Some(session.access.modified + self.access_ttl);
+        session.access.family = family.clone();
+
+        session.refresh = Some(CreateIdentity {
+            userid: session.access.userid.clone(),
+            ip: session.access.ip.clone(),
+            useragent: session.access.useragent.clone(),
+            family,
+            ..CreateIdentity::issue(refresh, KIND_REFRESH, Some(self.refresh_ttl))
+        });
+
+        session
+    }
+}
+
+/// Handler that exchanges a refresh token for a new access and refresh
+/// token pair.  The refresh token is read from the refresh header or, if it
+/// is not set, the `Authorization` header (bearer scheme), so clients that
+/// send their access token on every request can still refresh.  The new
+/// tokens are returned in the response header and refresh header.  Each
+/// refresh token may only be used once; presenting it again revokes every
+/// token issued from the same login
+///
+/// # Example
+///
+/// ```no_run
+/// # extern crate actix_web;
+/// # extern crate actix_web_sql_identity;
+/// # extern crate chrono;
+/// use actix_web::App;
+/// use actix_web::middleware::identity::IdentityService;
+/// use actix_web_sql_identity::SqlIdentityBuilder;
+/// use chrono::Duration;
+///
+/// let policy = SqlIdentityBuilder::new("sqlite://my.db")
+///                 .refresh_tokens(Duration::minutes(15), Duration::days(30))
+///                 .finish()
+///                 .expect("failed to open database");
+///
+/// let refresh = policy.refresh_handler();
+///
+/// let app = App::new()
+///     .resource("/refresh", |r| r.post().h(refresh))
+///     .middleware(IdentityService::new(policy));
+/// ```
+pub struct RefreshHandler(pub(crate) Rc<SqlIdentityInner>);
+
+impl<S: 'static> Handler<S> for RefreshHandler {
+    type Result = FutureResponse<HttpResponse>;
+
+    /// Exchanges the refresh token provided in a request for a new pair
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The HTTP request recieved
+    fn handle(&self, req: &HttpRequest<S>) -> Self::Result {
+        let config = match self.0.refresh {
+            Some(ref config) => config,
+            None => {
+                warn!("Refresh tokens are not enabled");
+                return Box::new(FutErr(
+                    SqlIdentityError::TokenNotFound.respond_as(self.0.on_error.as_ref(), StatusCode::NOT_FOUND),
+                ));
+            }
+        };
+
+        let presented = match TokenSource::Header(config.hdr.to_string())
+            .extract(req)
+            .or_else(|| TokenSource::Bearer.extract(req))
+        {
+            Some(presented) => presented,
+            None => return Box::new(FutErr(self.0.fail(SqlIdentityError::TokenRequired))),
+        };
+
+        let (ip, ua) = self.0.client_info(req);
+        let access_token = self.0.generate();
+        let refresh_token = self.0.generate();
+
+        let msg = RefreshIdentity {
+            lookup: token::Lookup::new(&presented, false),
+            access: CreateIdentity {
+                ip: Some(ip.clone()),
+                useragent: Some(ua.clone()),
+                ..CreateIdentity::issue(&access_token, KIND_ACCESS, Some(config.access_ttl))
+            },
+            refresh: CreateIdentity {
+                ip: Some(ip),
+                useragent: Some(ua),
+                ..CreateIdentity::issue(&refresh_token, KIND_REFRESH, Some(config.refresh_ttl))
+            },
+        };
+
+        let inner = Rc::clone(&self.0);
+
+        Box::new(
+            self.0
+                .addr
+                .send(msg)
+                .then(move |res| match SqlIdentityError::from_sent(res) {
+                    Ok(userid) => {
+                        info!("Issued new token pair for {}", userid);
+
+                        // The access tokens of the family were replaced
+                        inner.uncache(|i| i.userid == userid);
+
+                        let mut resp = HttpResponse::Ok().finish();
+                        inner.set_token(&access_token, &mut resp)?;
+                        if let Some(ref config) = inner.refresh {
+                            config.set_token(&inner, &refresh_token, &mut resp)?;
+                        }
+
+                        Ok(resp)
+                    }
+                    Err(e) => {
+                        warn!("WARN: {}", e);
+
+                        // The family the reused token belongs to is unknown
+                        if let SqlIdentityError::RefreshTokenReused = e {
+                            inner.uncache(|_| true);
+                        }
+
+                        Err(inner.fail(e))
+                    }
+                }),
+        )
+    }
+}
@@ -0,0 +1,188 @@
+//! Schema Validation
+//!
+//! Checks that the `identities` table (or the table configured instead) has
+//! every column this crate uses, with a type it can read and write, so a
+//! missing or mis-shaped table is reported when the policy is built instead
+//! of on the first login.
+
+// Diesel (SQL ORM) Imports
+use diesel::deserialize::FromSql;
+use diesel::query_builder::QueryFragment;
+use diesel::sql_types::{BigInt, Nullable, Text};
+use diesel::Connection;
+
+// Failure (error management system) Imports
+use failure::Error;
+
+use super::SqlIdentityError;
+use names::{Column, Names};
+use query::Query;
+
+/// Type of a column, as read by Diesel
+#[derive(Clone, Copy, Debug)]
+enum ColumnType {
+    BigInt,
+    Text,
+    Timestamp,
+}
+
+/// Columns of the `identities` table: column, type and whether it may be NULL
+const COLUMNS: &'static [(Column, ColumnType, bool)] = &[
+    (Column::Id, ColumnType::BigInt, false),
+    (Column::Token, ColumnType::Text, false),
+    (Column::UserId, ColumnType::Text, false),
+    (Column::Ip, ColumnType::Text, true),
+    (Column::UserAgent, ColumnType::Text, true),
+    (Column::Created, ColumnType::Timestamp, false),
+    (Column::Modified, ColumnType::Timestamp, false),
+    (Column::Expires, ColumnType::Timestamp, true),
+    (Column::Verifier, ColumnType::Text, true),
+    (Column::Previous, ColumnType::Text, true),
+    (Column::Rotated, ColumnType::Timestamp, true),
+    (Column::Kind, ColumnType::Text, false),
+    (Column::Family, ColumnType::Text, true),
+    (Column::Revoked, ColumnType::Timestamp, true),
+];
+
+/// Columns holding token hashes, which must fit a whole hash
+const HASHES: &'static [Column] = &[Column::Token, Column::Previous, Column::Family];
+
+/// Length of a token hash (base64 encoded SHA-256)
+const HASH_LENGTH: i64 = 44;
+
+/// How to describe the columns of a table, for a SQL variant
+pub(crate) struct Dialect {
+    /// Lists the name, type, nullability (`YES` or `NO`) and maximum length
+    /// in characters (NULL if unlimited) of each column of the table named
+    /// by its only placeholder
+    query: &'static str,
+
+    /// Database types accepted for a column type (lowercase, matched
+    /// against the start of the declared type)
+    types: fn(ColumnType) -> &'static [&'static str],
+}
+
+#[cfg(feature = "sqlite")]
+pub(crate) const SQLITE: Dialect = Dialect {
+    // SQLite does not enforce declared lengths
+    query: "SELECT name, type AS kind, CASE WHEN \"notnull\" = 0 THEN 'YES' ELSE 'NO' END AS nullable, \
+            NULL AS length FROM pragma_table_info(?)",
+    types: |ty| match ty {
+        ColumnType::BigInt => &["integer", "bigint"],
+        ColumnType::Text => &["text", "varchar", "char", "character", "clob"],
+        ColumnType::Timestamp => &["datetime", "timestamp"],
+    },
+};
+
+#[cfg(feature = "mysql")]
+pub(crate) const MYSQL: Dialect = Dialect {
+    query: "SELECT column_name AS name, data_type AS kind, is_nullable AS nullable, \
+            CAST(character_maximum_length AS SIGNED) AS length \
+            FROM information_schema.columns \
+            WHERE table_schema = DATABASE() AND table_name = ?",
+    types: |ty| match ty {
+        ColumnType::BigInt => &["bigint"],
+        ColumnType::Text => &["varchar", "char", "text", "tinytext", "mediumtext", "longtext"],
+        ColumnType::Timestamp => &["datetime", "timestamp"],
+    },
+};
+
+#[cfg(feature = "postgres")]
+pub(crate) const POSTGRES: Dialect = Dialect {
+    query: "SELECT column_name::text AS name, data_type::text AS kind, is_nullable::text AS nullable, \
+            character_maximum_length::bigint AS length \
+            FROM information_schema.columns \
+            WHERE table_schema = current_schema() AND table_name = ?",
+    types: |ty| match ty {
+        ColumnType::BigInt => &["bigint"],
+        ColumnType::Text => &["text", "character varying", "character"],
+        ColumnType::Timestamp => &["timestamp without time zone"],
+    },
+};
+
+/// A column of the table, as described by the database
+#[derive(QueryableByName)]
+struct Described {
+    #[sql_type = "Text"]
+    name: String,
+
+    #[sql_type = "Text"]
+    kind: String,
+
+    #[sql_type = "Text"]
+    nullable: String,
+
+    #[sql_type = "Nullable<BigInt>"]
+    length: Option<i64>,
+}
+
+/// Checks the columns of the `identities` table, failing with
+/// `SqlIdentityError::InvalidSchema` listing every problem found
+///
+/// # Arguments
+///
+/// * `conn` - Connection to the database
+/// * `dialect` - How to describe the table, for the SQL variant used
+/// * `names` - Names of the table and its columns
+pub(crate) fn check<C>(conn: &C, dialect: &Dialect, names: &Names) -> Result<(), Error>
+where
+    C: Connection,
+    Query: QueryFragment<C::Backend>,
+    String: FromSql<Text, C::Backend>,
+    i64: FromSql<BigInt, C::Backend>,
+{
+    let found: Vec<Described> = Query::new(dialect.query).bind(names.table()).load(conn)?;
+
+    if found.is_empty() {
+        let problem = format!("table {} does not exist", names.table());
+        return Err(SqlIdentityError::InvalidSchema(problem).into());
+    }
+
+    let mut problems = Vec::new();
+
+    for &(expected, ty, nullable) in COLUMNS {
+        let name = names.column(expected);
+
+        let column = match found.iter().find(|column| column.name.eq_ignore_ascii_case(name)) {
+            Some(column) => column,
+            None => {
+                problems.push(format!("column {} is missing", name));
+                continue;
+            }
+        };
+
+        let kind = column.kind.to_lowercase();
+        if !(dialect.types)(ty).iter().any(|accepted| kind.starts_with(accepted)) {
+            problems.push(format!(
+                "column {} has type {}, expected one of {}",
+                name,
+                column.kind,
+                (dialect.types)(ty).join(", ")
+            ));
+        }
+
+        match column.length {
+            Some(length) if length < HASH_LENGTH && HASHES.contains(&expected) => {
+                problems.push(format!(
+                    "column {} holds {} characters, expected at least {}",
+                    name, length, HASH_LENGTH
+                ));
+            }
+            _ => (),
+        }
+
+        // The primary key may be declared without NOT NULL
+        let found_nullable = column.nullable.eq_ignore_ascii_case("YES");
+        if found_nullable && !nullable && expected != Column::Id {
+            problems.push(format!("column {} must be NOT NULL", name));
+        } else if !found_nullable && nullable {
+            problems.push(format!("column {} must allow NULL", name));
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(SqlIdentityError::InvalidSchema(problems.join("; ")).into())
+    }
+}
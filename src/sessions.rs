@@ -0,0 +1,236 @@
+//! Session management
+//!
+//! Gives request handlers access to the sessions of the user making a
+//! request (e.g., to show where a user is logged in, or to log out
+//! everywhere after a password change).
+
+use std::rc::Rc;
+
+use chrono::NaiveDateTime;
+
+// Actix Web imports
+use actix_web::dev::Resource;
+use actix_web::error::{self, Error as ActixWebError};
+use actix_web::{FutureResponse, HttpRequest, HttpResponse, Scope};
+
+// Futures imports
+use futures::future::{err as FutErr, ok as FutOk};
+use futures::Future;
+
+use sql::SqlIdentityModel;
+
+use super::{SqlIdentityError, SqlIdentityInner};
+
+/// A session (logged in device) of a user
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Session {
+    /// Identifies the session, e.g. to revoke it
+    pub id: i64,
+
+    /// Address of the client, when the session was last used
+    pub ip: Option<String>,
+
+    /// User agent of the client, when the session was last used
+    pub user_agent: Option<String>,
+
+    /// When the user logged in
+    pub created: NaiveDateTime,
+
+    /// When the session was last used
+    pub modified: NaiveDateTime,
+
+    /// When the session expires, if it does
+    pub expires: Option<NaiveDateTime>,
+
+    /// True if this is the session of the request it was listed for
+    pub current: bool,
+}
+
+impl Session {
+    /// Builds a session record from an identity stored in the table
+    ///
+    /// # Arguments
+    ///
+    /// * `ident` - Identity loaded from the backend provider
+    /// * `current` - Id of the identity of the current request, if any
+    pub(crate) fn from_model(ident: SqlIdentityModel, current: Option<i64>) -> Session {
+        Session {
+            id: ident.id,
+            ip: ident.ip,
+            user_agent: ident.useragent,
+            created: ident.created,
+            modified: ident.modified,
+            expires: ident.expires,
+            current: current == Some(ident.id),
+        }
+    }
+}
+
+/// Identity a request was authenticated with, stored in the request
+/// extensions so it can be found by `RequestSessions`
+pub(crate) struct CurrentSession {
+    pub inner: Rc<SqlIdentityInner>,
+    pub id: Option<i64>,
+    pub userid: Option<String>,
+}
+
+/// Manages the sessions of the user making a request.  Only available on
+/// requests handled by a `SqlIdentityPolicy`
+///
+/// # Example
+///
+/// ```no_run
+/// # extern crate actix_web;
+/// # extern crate actix_web_sql_identity;
+/// # extern crate futures;
+/// use actix_web::{FutureResponse, HttpRequest, HttpResponse};
+/// use actix_web_sql_identity::RequestSessions;
+/// use futures::Future;
+///
+/// fn change_password(req: HttpRequest) -> FutureResponse<HttpResponse> {
+///     // ... update the password, then log out all other devices
+///     Box::new(req.forget_everywhere(true).map(|_| HttpResponse::Ok().finish()))
+/// }
+/// # fn main() {}
+/// ```
+pub trait RequestSessions {
+    /// Deletes every session of the user making this request.  Resolves to
+    /// the number of tokens deleted, or fails if the request is not
+    /// authenticated
+    ///
+    /// # Arguments
+    ///
+    /// * `keep_current` - True to keep the session of this request alive
+    fn forget_everywhere(&self, keep_current: bool) -> Box<Future<Item = usize, Error = ActixWebError>>;
+
+    /// Lists the sessions of the user making this request, most recently
+    /// used first, or fails if the request is not authenticated
+    fn sessions(&self) -> Box<Future<Item = Vec<Session>, Error = ActixWebError>>;
+
+    /// Revokes one of the sessions of the user making this request.
+    /// Resolves to true if the session existed, or fails if the request is
+    /// not authenticated
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Id of the session to revoke
+    fn revoke_session(&self, id: i64) -> Box<Future<Item = bool, Error = ActixWebError>>;
+}
+
+impl<S> RequestSessions for HttpRequest<S> {
+    fn forget_everywhere(&self, keep_current: bool) -> Box<Future<Item = usize, Error = ActixWebError>> {
+        match current(self) {
+            Ok((inner, id, userid)) => {
+                inner.delete_all(userid, if keep_current { id } else { None })
+            }
+            Err(e) => Box::new(FutErr(e)),
+        }
+    }
+
+    fn sessions(&self) -> Box<Future<Item = Vec<Session>, Error = ActixWebError>> {
+        match current(self) {
+            Ok((inner, id, userid)) => inner.list(userid, id),
+            Err(e) => Box::new(FutErr(e)),
+        }
+    }
+
+    fn revoke_session(&self, id: i64) -> Box<Future<Item = bool, Error = ActixWebError>> {
+        match current(self) {
+            Ok((inner, _, userid)) => inner.revoke(userid, id),
+            Err(e) => Box::new(FutErr(e)),
+        }
+    }
+}
+
+/// Adds session management endpoints to a scope, for the user making each
+/// request.  Every endpoint responds with `401 Unauthorized` when the
+/// request is not authenticated
+///
+/// * `GET /` - Lists sessions (as JSON), most recently used first
+/// * `DELETE /` - Revokes every session except the current one
+/// * `DELETE /{id}` - Revokes a single session, `404 Not Found` if the
+///   session does not exist
+///
+/// # Example
+///
+/// ```no_run
+/// # extern crate actix_web;
+/// # extern crate actix_web_sql_identity;
+/// use actix_web::App;
+/// use actix_web::middleware::identity::IdentityService;
+/// use actix_web_sql_identity::{sessions_scope, SqlIdentityBuilder};
+///
+/// let policy = SqlIdentityBuilder::new("sqlite://my.db")
+///                 .finish()
+///                 .expect("failed to open database");
+///
+/// let app = App::new()
+///     .middleware(IdentityService::new(policy))
+///     .scope("/sessions", sessions_scope);
+/// ```
+pub fn sessions_scope<S: 'static>(scope: Scope<S>) -> Scope<S> {
+    scope
+        .resource("", sessions_resource)
+        .resource("/", sessions_resource)
+        .resource("/{id}", |r| r.delete().f(revoke))
+}
+
+/// Routes the list and revoke others endpoints
+fn sessions_resource<S: 'static>(r: &mut Resource<S>) {
+    r.get().f(list);
+    r.delete().f(revoke_others);
+}
+
+/// Lists the sessions of the user making a request
+fn list<S>(req: &HttpRequest<S>) -> FutureResponse<HttpResponse> {
+    Box::new(
+        req.sessions()
+            .map(|sessions| HttpResponse::Ok().json(sessions)),
+    )
+}
+
+/// Revokes every session of the user making a request, except its own
+fn revoke_others<S>(req: &HttpRequest<S>) -> FutureResponse<HttpResponse> {
+    Box::new(
+        req.forget_everywhere(true)
+            .map(|_| HttpResponse::NoContent().finish()),
+    )
+}
+
+/// Revokes a single session of the user making a request
+fn revoke<S>(req: &HttpRequest<S>) -> FutureResponse<HttpResponse> {
+    let id = match req.match_info().query::<i64>("id") {
+        Ok(id) => id,
+        Err(_) => return Box::new(FutOk(HttpResponse::NotFound().finish())),
+    };
+
+    Box::new(req.revoke_session(id).map(|found| {
+        if found {
+            HttpResponse::NoContent().finish()
+        } else {
+            HttpResponse::NotFound().finish()
+        }
+    }))
+}
+
+/// Returns the provider, identity id and user id a request was
+/// authenticated with, or the error to respond with if it is not
+/// authenticated
+///
+/// # Arguments
+///
+/// * `req` - The HTTP request recieved
+fn current<S>(req: &HttpRequest<S>) -> Result<(Rc<SqlIdentityInner>, Option<i64>, String), ActixWebError> {
+    let extensions = req.extensions();
+
+    // Without a policy, there is no error handler to build the response
+    let session = match extensions.get::<CurrentSession>() {
+        Some(session) => session,
+        None => return Err(error::ErrorUnauthorized(SqlIdentityError::TokenRequired)),
+    };
+
+    match session.userid {
+        Some(ref userid) => Ok((Rc::clone(&session.inner), session.id, userid.clone())),
+        None => Err(session.inner.fail(SqlIdentityError::TokenRequired)),
+    }
+}
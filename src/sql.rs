@@ -1,16 +1,21 @@
 //! SQL Actor
 
+use std::sync::Arc;
+
 // Actix Imports
 use actix::prelude::{Actor, Handler, Message};
 use actix::sync::{SyncArbiter, SyncContext};
 use actix::Addr;
 
 use chrono::prelude::Utc;
-use chrono::NaiveDateTime;
+use chrono::{Duration, NaiveDateTime};
 
 // Diesel (SQL ORM) Imports
+use diesel::query_builder::QueryFragment;
+use diesel::query_source::QueryableByName;
 use diesel::r2d2::{ConnectionManager, Pool};
-use diesel::{self, ExpressionMethods, QueryDsl, RunQueryDsl};
+use diesel::sql_types::{BigInt, Nullable, Text, Timestamp};
+use diesel::Connection;
 
 #[cfg(feature = "sqlite")]
 use diesel::sqlite::SqliteConnection;
@@ -24,19 +29,19 @@ use diesel::pg::PgConnection;
 // Failure (error management system) Imports
 use failure::Error;
 
-use super::{SqlIdentity, SqlIdentityError};
+use super::{EvictionPolicy, SqlIdentity, SqlIdentityError};
+use migrations;
+use names::Names;
+use query::{placeholders, Query};
+use schema;
+use store::{Delete, IdentityStore};
+use token;
 
-table! {
-    identities (id) {
-        id -> Int8,
-        token -> Text,
-        userid -> Text,
-        ip -> Nullable<Text>,
-        useragent -> Nullable<Text>,
-        created -> Timestamp,
-        modified -> Timestamp,
-    }
-}
+/// Kind of a token used to authenticate requests
+pub const KIND_ACCESS: &'static str = "access";
+
+/// Kind of a token only used to obtain a new access token
+pub const KIND_REFRESH: &'static str = "refresh";
 
 /// Describes what type of SQL connection to create
 #[derive(Clone, Debug)]
@@ -44,17 +49,84 @@ pub enum Variant {
     Sqlite,
     Mysql,
     Pg,
+    Memory,
 }
 
-#[derive(Debug, Queryable)]
+/// An identity, as it is stored
+#[derive(Clone, Debug, Queryable, QueryableByName)]
 pub struct SqlIdentityModel {
+    #[sql_type = "BigInt"]
     pub id: i64,
+    #[sql_type = "Text"]
     pub token: String,
+    #[sql_type = "Text"]
     pub userid: String,
+    #[sql_type = "Nullable<Text>"]
     pub ip: Option<String>,
+    #[sql_type = "Nullable<Text>"]
     pub useragent: Option<String>,
+    #[sql_type = "Timestamp"]
     pub created: NaiveDateTime,
+    #[sql_type = "Timestamp"]
     pub modified: NaiveDateTime,
+    #[sql_type = "Nullable<Timestamp>"]
+    pub expires: Option<NaiveDateTime>,
+    #[sql_type = "Nullable<Text>"]
+    pub verifier: Option<String>,
+    #[sql_type = "Nullable<Text>"]
+    pub previous: Option<String>,
+    #[sql_type = "Nullable<Timestamp>"]
+    pub rotated: Option<NaiveDateTime>,
+    #[sql_type = "Text"]
+    pub kind: String,
+    #[sql_type = "Nullable<Text>"]
+    pub family: Option<String>,
+    #[sql_type = "Nullable<Timestamp>"]
+    pub revoked: Option<NaiveDateTime>,
+}
+
+/// The id of an identity
+#[derive(QueryableByName)]
+struct IdentityId {
+    #[sql_type = "BigInt"]
+    id: i64,
+}
+
+/// The family of an identity
+#[derive(QueryableByName)]
+struct IdentityFamily {
+    #[sql_type = "BigInt"]
+    id: i64,
+    #[sql_type = "Nullable<Text>"]
+    family: Option<String>,
+}
+
+/// The result of taking a named lock (MySQL), 1 if it was taken
+#[derive(QueryableByName)]
+#[cfg_attr(not(feature = "mysql"), allow(dead_code))]
+struct Locked {
+    #[sql_type = "Nullable<BigInt>"]
+    locked: Option<i64>,
+}
+
+/// Seconds to wait for the lock on the sessions of a user (MySQL)
+#[cfg(feature = "mysql")]
+const LOCK_TIMEOUT: i64 = 30;
+
+/// Seconds to wait for the lock on the schema, while another process
+/// migrates it (MySQL)
+#[cfg(feature = "mysql")]
+const MIGRATION_LOCK_TIMEOUT: i64 = 300;
+
+/// What a lock is taken on
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(not(any(feature = "mysql", feature = "postgres")), allow(dead_code))]
+enum Guarded<'a> {
+    /// The sessions of a user, while a session is created
+    Sessions(&'a str),
+
+    /// The table, while it is migrated
+    Schema,
 }
 
 /// Represents the different types of pools available
@@ -70,132 +142,213 @@ enum SqlPool {
     PgPool(Pool<ConnectionManager<PgConnection>>),
 }
 
-/// Represents an actix SQL actor
-pub struct SqlActor(SqlPool);
+/// Evaluates an expression with a connection taken from a pool, for each
+/// SQL variant that is enabled.  Useful for handlers that run several
+/// queries (e.g., in a transaction), which would otherwise be repeated
+/// once per variant
+macro_rules! with_conn {
+    ($pool:expr, |$conn:ident| $body:expr) => {
+        match $pool {
+            #[cfg(feature = "sqlite")]
+            SqlPool::SqlitePool(ref p) => {
+                let $conn: &SqliteConnection = &(*(p.get()?));
+                $body
+            }
 
-impl SqlActor {
-    /// Creates a new SQLite Actor, for a connection to a SQLite database
+            #[cfg(feature = "mysql")]
+            SqlPool::MySqlPool(ref p) => {
+                let $conn: &MysqlConnection = &(*(p.get()?));
+                $body
+            }
+
+            #[cfg(feature = "postgres")]
+            SqlPool::PgPool(ref p) => {
+                let $conn: &PgConnection = &(*(p.get()?));
+                $body
+            }
+        }
+    };
+}
+
+/// Identity store backed by a SQL database, through Diesel
+pub struct DieselStore {
+    pool: SqlPool,
+    names: Names,
+}
+
+impl DieselStore {
+    /// Creates a new store, using the default table and column names
+    ///
+    /// # Arguments
+    ///
+    /// * `pool` - Pool of connections to the database
+    fn new(pool: SqlPool) -> DieselStore {
+        DieselStore {
+            pool,
+            names: Names::new(),
+        }
+    }
+
+    /// Changes the names of the table and columns identities are stored in
+    ///
+    /// # Arguments
+    ///
+    /// * `names` - Names to use
+    pub(crate) fn renamed(mut self, names: Names) -> DieselStore {
+        self.names = names;
+        self
+    }
+
+    /// Creates a new store, for a connection to a SQLite database
     ///
     /// # Arguments
     ///
-    /// * `n` - Number of threads
     /// * `s` - SQLite connection string
-    pub fn sqlite(n: usize, s: &str) -> Result<Addr<SqlActor>, Error> {
+    pub fn sqlite(s: &str) -> Result<DieselStore, Error> {
         #[cfg(feature = "sqlite")]
         {
             let manager = ConnectionManager::<SqliteConnection>::new(s);
             let pool = Pool::builder().build(manager)?;
 
-            let addr = SyncArbiter::start(n, move || SqlActor(SqlPool::SqlitePool(pool.clone())));
-            Ok(addr)
+            Ok(DieselStore::new(SqlPool::SqlitePool(pool)))
         }
 
         #[cfg(not(feature = "sqlite"))]
         {
-            let _ = n;
             let _ = s;
             warn!("SQLite support not enabled!");
             Err(SqlIdentityError::SqlVariantNotSupported.into())
         }
     }
 
-    /// Creates a new MySQL Actor, for a connection to a MySQL database
+    /// Creates a new store, for a connection to a MySQL database
     ///
     /// # Arguments
     ///
-    /// * `n` - Number of threads
     /// * `s` - MySQL connection string
-    pub fn mysql(n: usize, s: &str) -> Result<Addr<SqlActor>, Error> {
+    pub fn mysql(s: &str) -> Result<DieselStore, Error> {
         #[cfg(feature = "mysql")]
         {
             let manager = ConnectionManager::<MysqlConnection>::new(s);
             let pool = Pool::builder().build(manager)?;
 
-            let addr = SyncArbiter::start(n, move || SqlActor(SqlPool::MySqlPool(pool.clone())));
-            Ok(addr)
+            Ok(DieselStore::new(SqlPool::MySqlPool(pool)))
         }
 
         #[cfg(not(feature = "mysql"))]
         {
-            let _ = n;
             let _ = s;
             warn!("MySQL support not enabled!");
             Err(SqlIdentityError::SqlVariantNotSupported.into())
         }
     }
 
-    /// Creates a new PostgresSQL Actor, for a connection to a PostgresSQL database
+    /// Creates a new store, for a connection to a PostgresSQL database
     ///
     /// # Arguments
     ///
-    /// * `n` - Number of threads
     /// * `s` - PostgresSQL connection string
-    pub fn pg(n: usize, s: &str) -> Result<Addr<SqlActor>, Error> {
+    pub fn pg(s: &str) -> Result<DieselStore, Error> {
         #[cfg(feature = "postgres")]
         {
             let manager = ConnectionManager::<PgConnection>::new(s);
             let pool = Pool::builder().build(manager)?;
 
-            let addr = SyncArbiter::start(n, move || SqlActor(SqlPool::PgPool(pool.clone())));
-            Ok(addr)
+            Ok(DieselStore::new(SqlPool::PgPool(pool)))
         }
 
         #[cfg(not(feature = "postgres"))]
         {
-            let _ = n;
             let _ = s;
             warn!("PostgreSQL support not enabled!");
             Err(SqlIdentityError::SqlVariantNotSupported.into())
         }
     }
-}
 
-impl Actor for SqlActor {
-    type Context = SyncContext<Self>;
-}
+    /// Creates the `identities` table, or upgrades it to the latest
+    /// version, and returns the version it is now at.  Servers sharing the
+    /// database migrate one at a time
+    pub fn migrate(&self) -> Result<i32, Error> {
+        let steps = match self.pool {
+            #[cfg(feature = "sqlite")]
+            SqlPool::SqlitePool(_) => &migrations::SQLITE,
 
-/// Searches for given identity based on a token value
-pub struct FindIdentity {
-    pub token: String,
-}
+            #[cfg(feature = "mysql")]
+            SqlPool::MySqlPool(_) => &migrations::MYSQL,
 
-impl Message for FindIdentity {
-    type Result = Result<SqlIdentityModel, Error>;
-}
+            #[cfg(feature = "postgres")]
+            SqlPool::PgPool(_) => &migrations::POSTGRES,
+        };
 
-impl Handler<FindIdentity> for SqlActor {
-    type Result = Result<SqlIdentityModel, Error>;
+        with_conn!(self.pool, |conn| {
+            self.lock(conn, Guarded::Schema, true)?;
+            let migrated = migrations::run(conn, steps, &self.names);
 
-    fn handle(&mut self, msg: FindIdentity, _: &mut Self::Context) -> Self::Result {
-        use self::identities::dsl::*;
+            if let Err(e) = self.lock(conn, Guarded::Schema, false) {
+                error!("Failed to release the migration lock: {:?}", e);
+            }
+
+            migrated
+        })
+    }
 
-        let session = match self.0 {
+    /// Checks that the `identities` table has every column expected, with
+    /// a compatible type.  Fails with `SqlIdentityError::InvalidSchema`,
+    /// listing every problem found, if not
+    pub fn validate(&self) -> Result<(), Error> {
+        let names = &self.names;
+
+        match self.pool {
             #[cfg(feature = "sqlite")]
-            SqlPool::SqlitePool(ref p) => {
-                let conn: &SqliteConnection = &(*(p.get()?));
-                identities.filter(token.eq(msg.token)).first(conn)?
-            }
+            SqlPool::SqlitePool(ref p) => schema::check(&*p.get()?, &schema::SQLITE, names),
 
             #[cfg(feature = "mysql")]
-            SqlPool::MySqlPool(ref p) => {
-                let conn: &MysqlConnection = &(*(p.get()?));
-                identities.filter(token.eq(msg.token)).first(conn)?
-            }
+            SqlPool::MySqlPool(ref p) => schema::check(&*p.get()?, &schema::MYSQL, names),
 
             #[cfg(feature = "postgres")]
-            SqlPool::PgPool(ref p) => {
-                let conn: &PgConnection = &(*(p.get()?));
-                identities.filter(token.eq(msg.token)).first(conn)?
-            }
-        };
+            SqlPool::PgPool(ref p) => schema::check(&*p.get()?, &schema::POSTGRES, names),
+        }
+    }
+}
 
-        Ok(session)
+/// Represents an actix SQL actor, running queries against an identity store
+pub struct SqlActor(Arc<IdentityStore>);
+
+impl SqlActor {
+    /// Starts the actors, sharing a single identity store
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - Number of threads
+    /// * `store` - Store to keep identities in
+    pub fn start(n: usize, store: Arc<IdentityStore>) -> Addr<SqlActor> {
+        SyncArbiter::start(n, move || SqlActor(Arc::clone(&store)))
     }
 }
 
-/// Inserts or Updates an Identity
-#[derive(Debug, AsChangeset)]
-#[table_name = "identities"]
+impl Actor for SqlActor {
+    type Context = SyncContext<Self>;
+}
+
+/// Searches for given identity based on a token value.  Identities are
+/// matched by either their current or previous (rotated) token
+pub struct FindIdentity {
+    /// Tokens to search for, exactly as they are stored in the table (e.g.,
+    /// the hash of a token, or the selector of a split token)
+    pub tokens: Vec<String>,
+
+    /// Kind of token to search for (e.g., `KIND_ACCESS`)
+    pub kind: &'static str,
+}
+
+/// Lists the identities (access tokens) of a user that have not expired or
+/// been revoked, most recently used first
+pub struct ListSessions {
+    pub userid: String,
+}
+
+/// Saves the last used time and client of an identity
+#[derive(Clone, Debug)]
 pub struct UpdateIdentity {
     pub id: i64,
     pub ip: Option<String>,
@@ -203,8 +356,8 @@ pub struct UpdateIdentity {
     pub modified: NaiveDateTime,
 }
 
-#[derive(Debug, Insertable)]
-#[table_name = "identities"]
+/// A newly issued token, to be inserted
+#[derive(Clone, Debug)]
 pub struct CreateIdentity {
     pub token: String,
     pub userid: String,
@@ -212,10 +365,70 @@ pub struct CreateIdentity {
     pub useragent: Option<String>,
     pub created: NaiveDateTime,
     pub modified: NaiveDateTime,
+    pub expires: Option<NaiveDateTime>,
+    pub verifier: Option<String>,
+    pub kind: String,
+    pub family: Option<String>,
+}
+
+/// Inserts a newly remembered identity, along with its refresh token (if
+/// enabled).  If the request was already authenticated, the identity it was
+/// authenticated with (and any tokens in its family) is deleted in the same
+/// transaction, so a token known before login cannot be used after it
+pub struct CreateSession {
+    pub access: CreateIdentity,
+    pub refresh: Option<CreateIdentity>,
+
+    /// Id of the identity being replaced, if any
+    pub replaces: Option<i64>,
+
+    /// Most sessions the user may have at once, if limited
+    pub max_sessions: Option<usize>,
+
+    /// What to do when the user already has the most sessions allowed
+    pub eviction: EvictionPolicy,
+}
+
+/// Exchanges a refresh token for a new access and refresh token pair.  Each
+/// refresh token may only be used once; if a refresh token that was already
+/// used is presented again, the whole family of tokens issued from the same
+/// login is deleted, as one of them has likely been stolen.  On success, the
+/// user id the new pair was issued to is returned
+pub struct RefreshIdentity {
+    /// Refresh token provided by the client
+    pub lookup: token::Lookup,
+
+    /// New access token (user id and family are taken from the refresh token)
+    pub access: CreateIdentity,
+
+    /// New refresh token (user id and family are taken from the refresh token)
+    pub refresh: CreateIdentity,
+}
+
+/// Replaces the token of an identity with a newly issued one.  The update
+/// only happens if the identity still has the token it was loaded with, so
+/// concurrent requests do not rotate the same token twice
+pub struct RotateIdentity {
+    pub id: i64,
+    pub current: String,
+    pub current_verifier: Option<String>,
+    pub changes: RotatedToken,
+}
+
+/// Changes made to an identity when its token is rotated
+#[derive(Debug)]
+pub struct RotatedToken {
+    pub token: String,
+    pub verifier: Option<String>,
+    pub previous: Option<String>,
+    pub rotated: NaiveDateTime,
+    pub ip: Option<String>,
+    pub useragent: Option<String>,
+    pub modified: NaiveDateTime,
 }
 
 impl UpdateIdentity {
-    pub fn update(ident: &SqlIdentity) -> UpdateIdentity {
+    pub(crate) fn update(ident: &SqlIdentity) -> UpdateIdentity {
         let now = Utc::now();
 
         UpdateIdentity {
@@ -226,16 +439,51 @@ impl UpdateIdentity {
         }
     }
 
-    pub fn create(ident: &SqlIdentity) -> CreateIdentity {
+    pub(crate) fn rotate(ident: &SqlIdentity, raw: &str) -> RotateIdentity {
         let now = Utc::now();
+        let current = ident.token.clone().unwrap_or_default();
+
+        // Split tokens keep their selector and replace the verifier, all
+        // other tokens are replaced entirely
+        let (stored, verifier, previous) = match token::split(raw) {
+            Some((_, verifier)) => (
+                current.clone(),
+                Some(token::hash(verifier)),
+                ident.verifier.clone(),
+            ),
+            None => (token::hash(raw), None, Some(current.clone())),
+        };
+
+        RotateIdentity {
+            id: ident.id,
+            current,
+            current_verifier: ident.verifier.clone(),
+            changes: RotatedToken {
+                token: stored,
+                verifier,
+                previous,
+                rotated: now.naive_utc(),
+                ip: ident.ip.clone(),
+                useragent: ident.user_agent.clone(),
+                modified: now.naive_utc(),
+            },
+        }
+    }
+
+    pub(crate) fn session(ident: &SqlIdentity) -> CreateSession {
+        CreateSession {
+            access: UpdateIdentity::create(ident),
+            refresh: None,
+            replaces: ident.replaces,
+            max_sessions: ident.inner.max_sessions,
+            eviction: ident.inner.eviction,
+        }
+    }
+
+    pub(crate) fn create(ident: &SqlIdentity) -> CreateIdentity {
+        let raw = ident.token.as_ref().map(|s| s.as_ref()).unwrap_or("");
 
         CreateIdentity {
-            token: ident
-                .token
-                .as_ref()
-                .map(|s| s.as_ref())
-                .unwrap_or("")
-                .to_string(),
             userid: ident
                 .identity
                 .as_ref()
@@ -245,46 +493,97 @@ impl UpdateIdentity {
             ip: ident.ip.clone(),
             useragent: ident.user_agent.clone(),
             created: ident.created,
-            modified: now.naive_utc(),
+            ..CreateIdentity::issue(raw, KIND_ACCESS, ident.inner.max_age)
         }
     }
 }
 
-impl Message for CreateIdentity {
-    type Result = Result<usize, Error>;
+impl CreateIdentity {
+    /// Prepares a newly issued token to be stored, without any user id,
+    /// client details or family
+    ///
+    /// # Arguments
+    ///
+    /// * `raw` - Raw token, as handed to the client
+    /// * `kind` - Kind of token (e.g., `KIND_ACCESS`)
+    /// * `ttl` - How long the token is valid for, if it expires
+    pub(crate) fn issue(raw: &str, kind: &str, ttl: Option<Duration>) -> CreateIdentity {
+        let now = Utc::now().naive_utc();
+
+        // Split tokens store the selector as-is and a hash of the verifier,
+        // all other tokens store a hash of the entire token
+        let (stored, verifier) = match token::split(raw) {
+            Some((selector, verifier)) => (selector.to_string(), Some(token::hash(verifier))),
+            None => (token::hash(raw), None),
+        };
+
+        CreateIdentity {
+            token: stored,
+            userid: String::new(),
+            ip: None,
+            useragent: None,
+            created: now,
+            modified: now,
+            expires: ttl.map(|ttl| now + ttl),
+            verifier,
+            kind: kind.to_string(),
+            family: None,
+        }
+    }
 }
 
-impl Handler<CreateIdentity> for SqlActor {
-    type Result = Result<usize, Error>;
+impl Message for FindIdentity {
+    type Result = Result<SqlIdentityModel, Error>;
+}
+
+impl Handler<FindIdentity> for SqlActor {
+    type Result = Result<SqlIdentityModel, Error>;
 
-    fn handle(&mut self, msg: CreateIdentity, _: &mut Self::Context) -> Self::Result {
-        use self::identities::dsl::*;
+    fn handle(&mut self, msg: FindIdentity, _: &mut Self::Context) -> Self::Result {
+        let session = match self.0.find(&msg.tokens, msg.kind)? {
+            Some(session) => session,
+            None => return Err(SqlIdentityError::TokenNotFound.into()),
+        };
 
-        match self.0 {
-            #[cfg(feature = "sqlite")]
-            SqlPool::SqlitePool(ref p) => {
-                let conn: &SqliteConnection = &(*(p.get()?));
-                let n = diesel::insert_into(identities).values(&msg).execute(conn)?;
+        // Expired sessions are never accepted, and retired refresh tokens
+        // are only kept to detect reuse
+        if session.revoked.is_some() {
+            return Err(SqlIdentityError::TokenNotFound.into());
+        }
 
-                Ok(n)
+        if let Some(exp) = session.expires {
+            if exp <= Utc::now().naive_utc() {
+                return Err(SqlIdentityError::TokenExpired.into());
             }
+        }
 
-            #[cfg(feature = "mysql")]
-            SqlPool::MySqlPool(ref p) => {
-                let conn: &MysqlConnection = &(*(p.get()?));
-                let n = diesel::insert_into(identities).values(&msg).execute(conn)?;
+        Ok(session)
+    }
+}
 
-                Ok(n)
-            }
+impl Message for CreateSession {
+    type Result = Result<Vec<i64>, Error>;
+}
 
-            #[cfg(feature = "postgres")]
-            SqlPool::PgPool(ref p) => {
-                let conn: &PgConnection = &(*(p.get()?));
-                let n = diesel::insert_into(identities).values(&msg).execute(conn)?;
+impl Handler<CreateSession> for SqlActor {
+    type Result = Result<Vec<i64>, Error>;
 
-                Ok(n)
-            }
-        }
+    fn handle(&mut self, msg: CreateSession, _: &mut Self::Context) -> Self::Result {
+        self.0.create(&msg)
+    }
+}
+
+impl Message for RefreshIdentity {
+    type Result = Result<String, Error>;
+}
+
+impl Handler<RefreshIdentity> for SqlActor {
+    type Result = Result<String, Error>;
+
+    fn handle(&mut self, msg: RefreshIdentity, _: &mut Self::Context) -> Self::Result {
+        self.0
+            .refresh(&msg)?
+            .ok_or_else(|| SqlIdentityError::RefreshTokenReused.into())
     }
 }
 
@@ -296,87 +595,524 @@ impl Handler<UpdateIdentity> for SqlActor {
     type Result = Result<usize, Error>;
 
     fn handle(&mut self, msg: UpdateIdentity, _: &mut Self::Context) -> Self::Result {
-        use self::identities::dsl::*;
+        self.0.touch(&msg)
+    }
+}
+
+impl Message for RotateIdentity {
+    type Result = Result<usize, Error>;
+}
+
+impl Handler<RotateIdentity> for SqlActor {
+    type Result = Result<usize, Error>;
+
+    fn handle(&mut self, msg: RotateIdentity, _: &mut Self::Context) -> Self::Result {
+        self.0.rotate(&msg)
+    }
+}
+
+impl Message for ListSessions {
+    type Result = Result<Vec<SqlIdentityModel>, Error>;
+}
+
+impl Handler<ListSessions> for SqlActor {
+    type Result = Result<Vec<SqlIdentityModel>, Error>;
 
-        match self.0 {
+    fn handle(&mut self, msg: ListSessions, _: &mut Self::Context) -> Self::Result {
+        let sessions = self.0.list(&msg.userid)?;
+
+        // Expired sessions are treated as if they do not exist
+        let now = Utc::now().naive_utc();
+        Ok(sessions
+            .into_iter()
+            .filter(|session| session.expires.map_or(true, |exp| exp > now))
+            .collect())
+    }
+}
+
+impl Message for Delete {
+    type Result = Result<usize, Error>;
+}
+
+impl Handler<Delete> for SqlActor {
+    type Result = Result<usize, Error>;
+
+    fn handle(&mut self, msg: Delete, _: &mut Self::Context) -> Self::Result {
+        self.0.delete(&msg)
+    }
+}
+
+impl DieselStore {
+    /// Renders a statement written with the default table and column names
+    ///
+    /// # Arguments
+    ///
+    /// * `sql` - Statement, with a `?` for each value
+    fn query(&self, sql: &str) -> Query {
+        Query::new(self.names.sql(sql))
+    }
+
+    /// Renders a statement selecting whole identities, with the columns
+    /// aliased to their default names
+    ///
+    /// # Arguments
+    ///
+    /// * `sql` - Rest of the statement (after the select list)
+    fn select(&self, sql: &str) -> Query {
+        Query::new(format!("SELECT {} {}", self.names.select(), self.names.sql(sql)))
+    }
+
+    /// Renders a statement inserting a newly issued token
+    ///
+    /// # Arguments
+    ///
+    /// * `new` - Token to insert
+    fn insert(&self, new: &CreateIdentity) -> Query {
+        self.query(
+            "INSERT INTO identities \
+             (token, userid, ip, useragent, created, modified, expires, verifier, kind, family) \
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        ).bind(&new.token)
+            .bind(&new.userid)
+            .bind(&new.ip)
+            .bind(&new.useragent)
+            .bind(new.created)
+            .bind(new.modified)
+            .bind(new.expires)
+            .bind(&new.verifier)
+            .bind(&new.kind)
+            .bind(&new.family)
+    }
+
+    /// Takes or releases a lock held across several statements, e.g. on the
+    /// sessions of a user across the transaction creating a session, so
+    /// concurrent logins of the same user cannot both see room under the
+    /// session limit, or on the table while it is migrated.  MySQL uses a
+    /// named lock and PostgreSQL an advisory lock; SQLite needs neither, as
+    /// it only allows one writer at a time
+    ///
+    /// # Arguments
+    ///
+    /// * `conn` - Connection the lock is held by
+    /// * `guarded` - What to lock
+    /// * `lock` - True to take the lock, false to release it
+    fn lock<C>(&self, conn: &C, guarded: Guarded, lock: bool) -> Result<(), Error>
+    where
+        C: Connection,
+        Query: QueryFragment<C::Backend>,
+        Locked: QueryableByName<C::Backend>,
+    {
+        match (&self.pool, lock) {
             #[cfg(feature = "sqlite")]
-            SqlPool::SqlitePool(ref p) => {
-                let conn: &SqliteConnection = &(*(p.get()?));
-                //let n = diesel::replace_into(identities).values(&msg).execute(conn)?;
-                let n = diesel::update(identities.find(msg.id))
-                    .set(&msg)
-                    .execute(conn)?;
+            (&SqlPool::SqlitePool(_), _) => {
+                let _ = (conn, guarded);
+            }
 
-                Ok(n)
+            #[cfg(feature = "mysql")]
+            (&SqlPool::MySqlPool(_), true) => {
+                let timeout = match guarded {
+                    Guarded::Sessions(_) => LOCK_TIMEOUT,
+                    Guarded::Schema => MIGRATION_LOCK_TIMEOUT,
+                };
+
+                let locked: Vec<Locked> = Query::new("SELECT GET_LOCK(?, ?) AS locked")
+                    .bind(&self.lock_key(guarded))
+                    .bind(timeout)
+                    .load(conn)?;
+
+                if locked.first().and_then(|l| l.locked) != Some(1) {
+                    error!("Timed out waiting for the lock on {:?}", guarded);
+                    return Err(SqlIdentityError::BackendUnavailable.into());
+                }
             }
 
             #[cfg(feature = "mysql")]
-            SqlPool::MySqlPool(ref p) => {
-                let conn: &MysqlConnection = &(*(p.get()?));
-                //let n = diesel::replace_into(identities).values(&msg).execute(conn)?;
-                let n = diesel::update(identities.find(msg.id))
-                    .set(&msg)
-                    .execute(conn)?;
+            (&SqlPool::MySqlPool(_), false) => {
+                Query::new("DO RELEASE_LOCK(?)").bind(&self.lock_key(guarded)).execute(conn)?;
+            }
 
-                Ok(n)
+            #[cfg(feature = "postgres")]
+            (&SqlPool::PgPool(_), true) => {
+                Query::new("SELECT pg_advisory_lock(hashtext(?))")
+                    .bind(&self.lock_key(guarded))
+                    .execute(conn)?;
             }
 
             #[cfg(feature = "postgres")]
-            SqlPool::PgPool(ref p) => {
-                let conn: &PgConnection = &(*(p.get()?));
-                let n = diesel::update(identities.find(msg.id))
-                    .set(&msg)
+            (&SqlPool::PgPool(_), false) => {
+                Query::new("SELECT pg_advisory_unlock(hashtext(?))")
+                    .bind(&self.lock_key(guarded))
                     .execute(conn)?;
-                //let n = diesel::insert_into(identities)
-                //.values(&msg)
-                //.on_conflict(token)
-                //.do_update()
-                //.set(&msg)
-                //.execute(conn)?;
-
-                Ok(n)
             }
         }
+
+        Ok(())
     }
-}
 
-/// Deletes an identity from the table (aka logout)
-pub struct DeleteIdentity {
-    pub token: String,
-}
+    /// Returns the name of a lock.  The name is hashed, as MySQL limits
+    /// lock names to 64 characters
+    ///
+    /// # Arguments
+    ///
+    /// * `guarded` - What to lock
+    #[cfg(any(feature = "mysql", feature = "postgres"))]
+    fn lock_key(&self, guarded: Guarded) -> String {
+        match guarded {
+            Guarded::Sessions(userid) => token::hash(&format!("{} {}", self.names.table(), userid)),
+            Guarded::Schema => token::hash(self.names.table()),
+        }
+    }
 
-impl Message for DeleteIdentity {
-    type Result = Result<usize, Error>;
+    /// Renders a statement deleting an identity, along with every token in
+    /// its family (if it has one)
+    ///
+    /// # Arguments
+    ///
+    /// * `ident` - Identity to delete
+    fn delete_with_family(&self, ident: &IdentityFamily) -> Query {
+        match ident.family {
+            Some(ref fam) => self.query("DELETE FROM identities WHERE family = ?").bind(fam),
+            None => self.query("DELETE FROM identities WHERE id = ?").bind(ident.id),
+        }
+    }
+
+    /// Renders a query listing the ids of an identity and every token in
+    /// its family (if it has one), as deleted by `delete_with_family`
+    ///
+    /// # Arguments
+    ///
+    /// * `ident` - Identity to list
+    fn select_with_family(&self, ident: &IdentityFamily) -> Query {
+        match ident.family {
+            Some(ref fam) => self.query("SELECT id AS id FROM identities WHERE family = ?").bind(fam),
+            None => self.query("SELECT id AS id FROM identities WHERE id = ?").bind(ident.id),
+        }
+    }
 }
 
-impl Handler<DeleteIdentity> for SqlActor {
-    type Result = Result<usize, Error>;
+impl IdentityStore for DieselStore {
+    fn find(&self, tokens: &[String], kind: &str) -> Result<Option<SqlIdentityModel>, Error> {
+        let list = placeholders(tokens.len());
+        let query = self
+            .select(&format!(
+                "FROM identities WHERE (token IN ({0}) OR previous IN ({0})) AND kind = ?",
+                list
+            ))
+            .bind_all(tokens)
+            .bind_all(tokens)
+            .bind(kind);
+
+        let sessions: Vec<SqlIdentityModel> = with_conn!(self.pool, |conn| query.load(conn)?);
+
+        Ok(sessions.into_iter().next())
+    }
 
-    fn handle(&mut self, msg: DeleteIdentity, _: &mut Self::Context) -> Self::Result {
-        use self::identities::dsl::*;
+    fn create(&self, session: &CreateSession) -> Result<Vec<i64>, Error> {
+        let userid = &session.access.userid;
+        let limited = session.max_sessions.is_some();
 
-        match self.0 {
-            #[cfg(feature = "sqlite")]
-            SqlPool::SqlitePool(ref p) => {
-                let conn: &SqliteConnection = &(*(p.get()?));
-                let n = diesel::delete(identities.filter(token.eq(msg.token))).execute(conn)?;
+        with_conn!(self.pool, |conn| {
+            if limited {
+                self.lock(conn, Guarded::Sessions(userid), true)?;
+            }
 
-                Ok(n)
+            let created = conn.transaction::<_, Error, _>(|| {
+                if let Some(replaces) = session.replaces {
+                    let replaced: Vec<IdentityFamily> = self
+                        .query("SELECT id AS id, family AS family FROM identities WHERE id = ?")
+                        .bind(replaces)
+                        .load(conn)?;
+
+                    for ident in &replaced {
+                        self.delete_with_family(ident).execute(conn)?;
+                    }
+                }
+
+                let mut evicted = Vec::new();
+
+                if let Some(max) = session.max_sessions {
+                    // A session is a family of tokens (or a single access
+                    // token), live while any of its tokens is, so a refresh
+                    // token counts even after its access token expired
+                    let now = Utc::now().naive_utc();
+                    let active: Vec<IdentityFamily> = self
+                        .query(
+                            "SELECT MIN(id) AS id, family AS family FROM identities \
+                             WHERE userid = ? AND revoked IS NULL \
+                             AND (expires IS NULL OR expires > ?) \
+                             GROUP BY family, CASE WHEN family IS NULL THEN id END \
+                             ORDER BY MAX(modified) ASC",
+                        ).bind(userid)
+                        .bind(now)
+                        .load(conn)?;
+
+                    let excess = active.len() as i64 + 1 - max as i64;
+
+                    if excess > 0 {
+                        if session.eviction == EvictionPolicy::Reject {
+                            return Err(SqlIdentityError::TooManySessions.into());
+                        }
+
+                        // Evict the least recently used sessions, along with
+                        // any tokens in their families
+                        for victim in active.iter().take(excess as usize) {
+                            info!("Evicting identity {} of {}", victim.id, userid);
+                            let ids: Vec<IdentityId> = self.select_with_family(victim).load(conn)?;
+                            evicted.extend(ids.into_iter().map(|ident| ident.id));
+                            self.delete_with_family(victim).execute(conn)?;
+                        }
+                    }
+                }
+
+                self.insert(&session.access).execute(conn)?;
+                if let Some(ref refresh) = session.refresh {
+                    self.insert(refresh).execute(conn)?;
+                }
+
+                Ok(evicted)
+            });
+
+            if limited {
+                if let Err(e) = self.lock(conn, Guarded::Sessions(userid), false) {
+                    error!("Failed to release the session lock of {}: {:?}", userid, e);
+                }
             }
 
-            #[cfg(feature = "mysql")]
-            SqlPool::MySqlPool(ref p) => {
-                let conn: &MysqlConnection = &(*(p.get()?));
-                let n = diesel::delete(identities.filter(token.eq(msg.token))).execute(conn)?;
-                Ok(n)
+            created
+        })
+    }
+
+    fn touch(&self, update: &UpdateIdentity) -> Result<usize, Error> {
+        let query = self
+            .query("UPDATE identities SET ip = ?, useragent = ?, modified = ? WHERE id = ?")
+            .bind(&update.ip)
+            .bind(&update.useragent)
+            .bind(update.modified)
+            .bind(update.id);
+
+        with_conn!(self.pool, |conn| {
+            let n = query.execute(conn)?;
+
+            Ok(n)
+        })
+    }
+
+    fn rotate(&self, rotate: &RotateIdentity) -> Result<usize, Error> {
+        let changes = &rotate.changes;
+        let current_verifier = rotate.current_verifier.clone().unwrap_or_default();
+        let query = self
+            .query(
+                "UPDATE identities \
+                 SET token = ?, verifier = ?, previous = ?, rotated = ?, ip = ?, useragent = ?, modified = ? \
+                 WHERE id = ? AND token = ? AND COALESCE(verifier, '') = ?",
+            ).bind(&changes.token)
+            .bind(&changes.verifier)
+            .bind(&changes.previous)
+            .bind(changes.rotated)
+            .bind(&changes.ip)
+            .bind(&changes.useragent)
+            .bind(changes.modified)
+            .bind(rotate.id)
+            .bind(&rotate.current)
+            .bind(&current_verifier);
+
+        with_conn!(self.pool, |conn| {
+            let n = query.execute(conn)?;
+
+            Ok(n)
+        })
+    }
+
+    fn refresh(&self, msg: &RefreshIdentity) -> Result<Option<String>, Error> {
+        let lookup = &msg.lookup;
+        let mut access = msg.access.clone();
+        let mut refresh = msg.refresh.clone();
+
+        // Returns None when a used refresh token is presented again, so the
+        // family is deleted when the transaction commits
+        with_conn!(self.pool, |conn| conn.transaction::<_, Error, _>(|| {
+            let now = Utc::now().naive_utc();
+            let session: SqlIdentityModel = self
+                .select(&format!(
+                    "FROM identities WHERE token IN ({}) AND kind = ?",
+                    placeholders(lookup.tokens.len())
+                ))
+                .bind_all(&lookup.tokens)
+                .bind(KIND_REFRESH)
+                .load(conn)?
+                .into_iter()
+                .next()
+                .ok_or(SqlIdentityError::TokenNotFound)?;
+
+            if !lookup.matches(&session, Duration::zero()) {
+                return Err(SqlIdentityError::TokenNotFound.into());
             }
 
-            #[cfg(feature = "postgres")]
-            SqlPool::PgPool(ref p) => {
-                let conn: &PgConnection = &(*(p.get()?));
-                let n = diesel::delete(identities.filter(token.eq(msg.token))).execute(conn)?;
-                Ok(n)
+            let fam = session.family.clone().unwrap_or_else(|| session.token.clone());
+
+            if session.revoked.is_some() {
+                self.query("DELETE FROM identities WHERE family = ?")
+                    .bind(&fam)
+                    .execute(conn)?;
+                return Ok(None);
+            }
+
+            if session.expires.map_or(false, |exp| exp <= now) {
+                return Err(SqlIdentityError::TokenExpired.into());
+            }
+
+            // Retire the refresh token (kept to detect reuse) and the access
+            // tokens issued alongside it.  If another request retired it
+            // since it was read, the token was used twice
+            let retired = self
+                .query("UPDATE identities SET revoked = ? WHERE id = ? AND revoked IS NULL")
+                .bind(now)
+                .bind(session.id)
+                .execute(conn)?;
+
+            if retired == 0 {
+                self.query("DELETE FROM identities WHERE family = ?")
+                    .bind(&fam)
+                    .execute(conn)?;
+                return Ok(None);
+            }
+
+            self.query("DELETE FROM identities WHERE family = ? AND kind = ?")
+                .bind(&fam)
+                .bind(KIND_ACCESS)
+                .execute(conn)?;
+
+            access.userid = session.userid.clone();
+            access.family = Some(fam.clone());
+            refresh.userid = session.userid.clone();
+            refresh.family = Some(fam);
+
+            self.insert(&access).execute(conn)?;
+            self.insert(&refresh).execute(conn)?;
+
+            Ok(Some(session.userid))
+        }))
+    }
+
+    fn delete(&self, target: &Delete) -> Result<usize, Error> {
+        match *target {
+            Delete::Token(ref stored) => {
+                let query = self.query("DELETE FROM identities WHERE token = ?").bind(stored);
+
+                with_conn!(self.pool, |conn| {
+                    let n = query.execute(conn)?;
+
+                    Ok(n)
+                })
+            }
+
+            Delete::Family(ref fam) => {
+                let query = self.query("DELETE FROM identities WHERE family = ?").bind(fam);
+
+                with_conn!(self.pool, |conn| {
+                    let n = query.execute(conn)?;
+
+                    Ok(n)
+                })
+            }
+
+            Delete::Session { ref userid, id } => {
+                with_conn!(self.pool, |conn| conn.transaction::<_, Error, _>(|| {
+                    let sessions: Vec<IdentityFamily> = self
+                        .query("SELECT id AS id, family AS family FROM identities WHERE userid = ? AND id = ?")
+                        .bind(userid)
+                        .bind(id)
+                        .load(conn)?;
+
+                    let mut n = 0;
+                    for session in &sessions {
+                        n += self.delete_with_family(session).execute(conn)?;
+                    }
+
+                    Ok(n)
+                }))
+            }
+
+            Delete::User { ref userid, keep } => {
+                with_conn!(self.pool, |conn| conn.transaction::<_, Error, _>(|| {
+                    let keep_family: Option<String> = match keep {
+                        Some(keep) => self
+                            .query("SELECT id AS id, family AS family FROM identities WHERE id = ?")
+                            .bind(keep)
+                            .load::<_, IdentityFamily>(conn)?
+                            .into_iter()
+                            .next()
+                            .and_then(|ident| ident.family),
+                        None => None,
+                    };
+
+                    let n = match keep_family {
+                        Some(fam) => self
+                            .query(
+                                "DELETE FROM identities WHERE userid = ? AND id <> ? \
+                                 AND (family IS NULL OR family <> ?)",
+                            ).bind(userid)
+                            .bind(keep.unwrap_or(-1))
+                            .bind(&fam)
+                            .execute(conn)?,
+                        None => self
+                            .query("DELETE FROM identities WHERE userid = ? AND id <> ?")
+                            .bind(userid)
+                            .bind(keep.unwrap_or(-1))
+                            .execute(conn)?,
+                    };
+
+                    Ok(n)
+                }))
+            }
+
+            Delete::Expired { batch, idle_timeout } => {
+                let now = Utc::now().naive_utc();
+                let query = match idle_timeout {
+                    Some(timeout) => self
+                        .query(
+                            "SELECT id AS id FROM identities \
+                             WHERE expires <= ? OR (kind = ? AND modified <= ?) LIMIT ?",
+                        ).bind(now)
+                        .bind(KIND_ACCESS)
+                        .bind(now - timeout),
+                    None => self
+                        .query("SELECT id AS id FROM identities WHERE expires <= ? LIMIT ?")
+                        .bind(now),
+                }.bind(batch as i64);
+
+                with_conn!(self.pool, |conn| {
+                    let ids: Vec<i64> = query
+                        .load::<_, IdentityId>(conn)?
+                        .into_iter()
+                        .map(|ident| ident.id)
+                        .collect();
+
+                    if ids.is_empty() {
+                        return Ok(0);
+                    }
+
+                    let n = self
+                        .query(&format!("DELETE FROM identities WHERE id IN ({})", placeholders(ids.len())))
+                        .bind_all(&ids)
+                        .execute(conn)?;
+
+                    Ok(n)
+                })
             }
         }
     }
+
+    fn list(&self, userid: &str) -> Result<Vec<SqlIdentityModel>, Error> {
+        let query = self
+            .select(
+                "FROM identities WHERE userid = ? AND kind = ? AND revoked IS NULL \
+                 ORDER BY modified DESC",
+            ).bind(userid)
+            .bind(KIND_ACCESS);
+
+        let sessions: Vec<SqlIdentityModel> = with_conn!(self.pool, |conn| query.load(conn)?);
+
+        Ok(sessions)
+    }
 }
@@ -0,0 +1,111 @@
+//! Identity Storage
+//!
+//! Identities are kept in an `IdentityStore`.  By default, a SQL database
+//! is used (see `DieselStore`), or memory for `memory://` connection strings
+//! (see `MemoryStore`), but any other store may be plugged in with
+//! `SqlIdentityBuilder::store` (e.g., a test double).
+//!
+//! Stores are called from a pool of synchronous worker threads, so they may
+//! block.  Expired and revoked identities do not need to be filtered out by
+//! `find` or `list`; that is done by the caller.
+
+use chrono::Duration;
+
+// Failure (error management system) Imports
+use failure::Error;
+
+pub use memory::MemoryStore;
+pub use sql::{
+    CreateIdentity, CreateSession, DieselStore, RefreshIdentity, RotateIdentity, RotatedToken,
+    SqlIdentityModel, UpdateIdentity, KIND_ACCESS, KIND_REFRESH,
+};
+pub use token::Lookup;
+
+/// Storage for identities (tokens and the users they belong to)
+pub trait IdentityStore: Send + Sync {
+    /// Finds the identity with one of the given tokens (current or previous)
+    /// of the given kind, if any
+    ///
+    /// # Arguments
+    ///
+    /// * `tokens` - Tokens to search for, exactly as they are stored
+    /// * `kind` - Kind of token to search for (e.g., `KIND_ACCESS`)
+    fn find(&self, tokens: &[String], kind: &str) -> Result<Option<SqlIdentityModel>, Error>;
+
+    /// Inserts a newly remembered identity (and refresh token, if any),
+    /// deleting the identity it replaces and enforcing the session limit,
+    /// all at once.  Logins of the same user may arrive together, so the
+    /// sessions are counted and the new one inserted while holding a lock
+    /// on that user's sessions.  Fails with
+    /// `SqlIdentityError::TooManySessions` if the limit is reached and the
+    /// eviction policy rejects new sessions.  Returns the ids of the
+    /// identities evicted to stay within the limit (including every token
+    /// in their families)
+    ///
+    /// # Arguments
+    ///
+    /// * `session` - Identity to insert
+    fn create(&self, session: &CreateSession) -> Result<Vec<i64>, Error>;
+
+    /// Saves the last used time and client of an identity.  Returns how
+    /// many identities were updated
+    ///
+    /// # Arguments
+    ///
+    /// * `update` - Identity to update
+    fn touch(&self, update: &UpdateIdentity) -> Result<usize, Error>;
+
+    /// Replaces the token of an identity, only if it still has the token it
+    /// was loaded with.  Returns how many identities were updated
+    ///
+    /// # Arguments
+    ///
+    /// * `rotate` - Identity to rotate
+    fn rotate(&self, rotate: &RotateIdentity) -> Result<usize, Error>;
+
+    /// Exchanges a refresh token for a new pair, all at once.  Returns the
+    /// user id the pair was issued to, or None if the refresh token was
+    /// already used, including by a concurrent exchange (after deleting its
+    /// family)
+    ///
+    /// # Arguments
+    ///
+    /// * `refresh` - Refresh token and the new pair
+    fn refresh(&self, refresh: &RefreshIdentity) -> Result<Option<String>, Error>;
+
+    /// Deletes identities.  Returns how many tokens were deleted
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - Identities to delete
+    fn delete(&self, target: &Delete) -> Result<usize, Error>;
+
+    /// Lists the access tokens of a user that have not been revoked, most
+    /// recently used first
+    ///
+    /// # Arguments
+    ///
+    /// * `userid` - User to list the identities of
+    fn list(&self, userid: &str) -> Result<Vec<SqlIdentityModel>, Error>;
+}
+
+/// Describes which identities to delete
+#[derive(Clone, Debug)]
+pub enum Delete {
+    /// A single token, exactly as it is stored (aka logout)
+    Token(String),
+
+    /// Every token issued from the same login
+    Family(String),
+
+    /// A single identity of a user, along with its family
+    Session { userid: String, id: i64 },
+
+    /// Every identity of a user (aka log out everywhere), except one
+    /// identity (and its family), if given
+    User { userid: String, keep: Option<i64> },
+
+    /// A batch of identities that have expired, or gone idle (access
+    /// tokens only) if an idle timeout is given
+    Expired { batch: usize, idle_timeout: Option<Duration> },
+}
@@ -0,0 +1,224 @@
+//! Token generation and hashing
+
+use base64;
+
+use actix_web::HttpRequest;
+
+use chrono::prelude::Utc;
+use chrono::Duration;
+
+use constant_time_eq::constant_time_eq;
+
+// Rand Imports (thread secure!)
+use rand::{self, Rng};
+
+// Sha2 (hashing) Imports
+use sha2::{Digest, Sha256};
+
+use sql::SqlIdentityModel;
+
+/// Describes where in a request a token may be found
+#[derive(Clone, Debug, PartialEq)]
+pub enum TokenSource {
+    /// The `Authorization` header, using the bearer scheme (e.g.,
+    /// `Authorization: Bearer <token>`).  Any other scheme is ignored
+    Bearer,
+
+    /// A custom header, containing only the token
+    Header(String),
+
+    /// A cookie, containing only the token
+    Cookie(String),
+
+    /// A query string parameter (e.g., for WebSocket upgrades, where
+    /// browsers cannot set headers).  The token must be percent-encoded
+    Query(String),
+}
+
+impl TokenSource {
+    /// Extracts a token from a request, if one is provided by this source
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The HTTP request recieved
+    pub(crate) fn extract<S>(&self, req: &HttpRequest<S>) -> Option<String> {
+        match *self {
+            TokenSource::Bearer => {
+                let value = req.headers().get("Authorization")?.to_str().ok()?;
+                let mut iter = value.splitn(2, ' ');
+                let scheme = iter.next()?;
+                let token = iter.next()?.trim();
+
+                if scheme.eq_ignore_ascii_case("bearer") && !token.is_empty() {
+                    Some(token.to_string())
+                } else {
+                    None
+                }
+            }
+
+            TokenSource::Header(ref name) => req.headers()
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.trim())
+                .filter(|value| !value.is_empty())
+                .map(|value| value.to_string()),
+
+            TokenSource::Cookie(ref name) => req.cookie(name)
+                .map(|cookie| cookie.value().to_string())
+                .filter(|value| !value.is_empty()),
+
+            TokenSource::Query(ref name) => req.query()
+                .get(name)
+                .filter(|value| !value.is_empty())
+                .cloned(),
+        }
+    }
+}
+
+/// A token provided by a client, prepared for looking up and verifying
+/// the identity it belongs to
+pub struct Lookup {
+    /// Values to search for, exactly as they are stored in the table
+    pub tokens: Vec<String>,
+
+    /// Verifier of a split token, if the token is a split token
+    verifier: Option<String>,
+}
+
+impl Lookup {
+    /// Prepares a raw token for lookup.  Split (selector.verifier) tokens
+    /// are looked up by their selector and then verified, all other tokens
+    /// are looked up by their hash (or as-is, if legacy tokens are allowed)
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - Raw token, as provided by the client
+    /// * `legacy` - True to also look up the token in plaintext
+    pub(crate) fn new(token: &str, legacy: bool) -> Lookup {
+        match split(token) {
+            Some((selector, verifier)) => Lookup {
+                tokens: vec![selector.to_string()],
+                verifier: Some(verifier.to_string()),
+            },
+            None => Lookup {
+                tokens: Some(hash(token))
+                    .into_iter()
+                    .chain(if legacy { Some(token.to_string()) } else { None })
+                    .collect(),
+                verifier: None,
+            },
+        }
+    }
+
+    /// Checks this token against the current token of an identity, or its
+    /// previous token if the identity was rotated within the grace period
+    ///
+    /// # Arguments
+    ///
+    /// * `ident` - Identity found when searching for this token
+    /// * `grace` - How long a previous token remains valid after rotation
+    pub fn matches(&self, ident: &SqlIdentityModel, grace: Duration) -> bool {
+        if self.is_current(ident) {
+            return true;
+        }
+
+        let in_grace = ident
+            .rotated
+            .map_or(false, |rotated| rotated + grace > Utc::now().naive_utc());
+
+        // Split tokens keep their selector when rotated, and only replace the
+        // verifier, all other tokens are replaced entirely
+        let previous = ident.previous.as_ref().map(|s| s.as_ref());
+        in_grace && match ident.verifier {
+            Some(_) => self.check(&ident.token, previous),
+            None => previous.map_or(false, |previous| self.check(previous, None)),
+        }
+    }
+
+    /// Checks this token against the current token of an identity only
+    ///
+    /// # Arguments
+    ///
+    /// * `ident` - Identity found when searching for this token
+    pub fn is_current(&self, ident: &SqlIdentityModel) -> bool {
+        let verifier = ident.verifier.as_ref().map(|s| s.as_ref());
+        self.check(&ident.token, verifier)
+    }
+
+    /// Checks this token against a stored token and verifier hash
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - Token stored in the table (hash, or selector)
+    /// * `verifier` - Verifier hash stored in the table, if any
+    fn check(&self, token: &str, verifier: Option<&str>) -> bool {
+        let presented = self.verifier.as_ref().map(|s| s.as_ref());
+        self.tokens.iter().any(|t| t == token) && verify(presented, verifier)
+    }
+}
+
+/// Generates a new random token, suitable for handing to a client
+pub fn generate() -> String {
+    random(24)
+}
+
+/// Generates a new random `selector.verifier` token, suitable for handing
+/// to a client
+pub fn generate_split() -> String {
+    format!("{}.{}", random(12), random(24))
+}
+
+/// Generates a random, base64-encoded value
+///
+/// # Arguments
+///
+/// * `len` - Number of random bytes to generate
+fn random(len: usize) -> String {
+    let mut arr = vec![0u8; len];
+    rand::thread_rng().fill(&mut arr[..]);
+    base64::encode(&arr)
+}
+
+/// Splits a `selector.verifier` token into its parts.  Returns None if
+/// the token is not a split token
+///
+/// # Arguments
+///
+/// * `token` - Raw token, as provided by the client
+pub fn split(token: &str) -> Option<(&str, &str)> {
+    let mut iter = token.splitn(2, '.');
+
+    match (iter.next(), iter.next()) {
+        (Some(selector), Some(verifier)) => Some((selector, verifier)),
+        _ => None,
+    }
+}
+
+/// Checks the verifier provided by a client against the verifier hash
+/// stored in the database, in constant time.  Tokens without a verifier
+/// only match identities stored without one (and vice versa)
+///
+/// # Arguments
+///
+/// * `presented` - Verifier provided by the client, if any
+/// * `stored` - Verifier hash stored in the database, if any
+pub fn verify(presented: Option<&str>, stored: Option<&str>) -> bool {
+    match (presented, stored) {
+        (Some(presented), Some(stored)) => {
+            constant_time_eq(hash(presented).as_bytes(), stored.as_bytes())
+        }
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// Hashes a token (SHA-256) into the form that is stored in the database.
+/// Raw tokens are never stored, so read access to the identities table
+/// is not enough to impersonate a user
+///
+/// # Arguments
+///
+/// * `token` - Raw token, as provided by the client
+pub fn hash(token: &str) -> String {
+    base64::encode(&Sha256::digest(token.as_bytes()))
+}
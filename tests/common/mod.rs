@@ -2,23 +2,34 @@
 //!
 //! Module: Tests/common
 
-use actix_web_sql_identity::SqlIdentityBuilder;
+use actix_web_sql_identity::{sessions_scope, RequestSessions, SqlIdentityBuilder};
 
-use actix_web::client::{ClientRequest, ClientRequestBuilder};
-use actix_web::http::StatusCode;
+use actix_web::client::{ClientRequest, ClientRequestBuilder, ClientResponse};
+use actix_web::http::{Cookie, Method, StatusCode};
 use actix_web::middleware::identity::{IdentityService, RequestIdentity};
 use actix_web::test::TestServer;
-use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use actix_web::{App, FutureResponse, HttpMessage, HttpRequest, HttpResponse};
 
 use dotenv;
 
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures::Future;
+
+use serde_json::{self, Value};
+
 const RESPONSE_HEADER: &'static str = "test-auth";
+const REFRESH_HEADER: &'static str = "test-refresh";
+
+/// Number of in-memory stores opened, so each test gets its own
+static MEMORY_STORES: AtomicUsize = AtomicUsize::new(0);
 
 /// The different kinds of SQL languanges supported
 pub enum SqlVariant {
     Sqlite,
     MySql,
     Postgres,
+    Memory,
 }
 
 /// Builds a new test server using a specific SQL variant and
@@ -29,9 +40,20 @@ pub enum SqlVariant {
 ///
 /// * `sql` - The SQL variant to use (Sqlite, MySQL, or PostgreSQL)
 pub fn build_test_server_from_env(variant: SqlVariant) -> TestServer {
+    build_test_server(env_uri(variant))
+}
+
+/// Reads the connection string for a specific SQL variant from
+/// the test environment file.  In-memory stores need no setup, and a new
+/// one is used every time
+///
+/// # Arguments
+///
+/// * `variant` - The SQL variant to use (Sqlite, MySQL, PostgreSQL, or Memory)
+pub fn env_uri(variant: SqlVariant) -> String {
     dotenv::from_filename("tests/test.env").ok();
 
-    let uri = match variant {
+    match variant {
         SqlVariant::Sqlite => format!(
             "{}/{}",
             dotenv::var("SQLITE_PATH").unwrap(),
@@ -51,9 +73,11 @@ pub fn build_test_server_from_env(variant: SqlVariant) -> TestServer {
             dotenv::var("PG_HOST").unwrap(),
             dotenv::var("PG_DB").unwrap()
         ),
-    };
-
-    build_test_server(uri)
+        SqlVariant::Memory => format!(
+            "memory://test-{}",
+            MEMORY_STORES.fetch_add(1, Ordering::SeqCst)
+        ),
+    }
 }
 
 /// Builds a new test server using a specific SQL variant and
@@ -64,19 +88,41 @@ pub fn build_test_server_from_env(variant: SqlVariant) -> TestServer {
 ///
 /// * `uri` - Database connection string (e.g., sqlite://, mysql://, postgres://)
 pub fn build_test_server<S: Into<String>>(uri: S) -> TestServer {
+    build_test_server_with(uri, |policy| policy)
+}
+
+/// Builds a new test server, allowing the identity policy to be customized
+/// before it is finished.  Returns a new TestServer instance
+///
+/// # Arguments
+///
+/// * `uri` - Database connection string (e.g., sqlite://, mysql://, postgres://)
+/// * `customize` - Function to apply extra options to the identity policy
+pub fn build_test_server_with<S, F>(uri: S, customize: F) -> TestServer
+where
+    S: Into<String>,
+    F: Fn(SqlIdentityBuilder) -> SqlIdentityBuilder + Clone + Send + 'static,
+{
     let uri = uri.into();
     println!("Connecting to: {}", uri);
 
-    TestServer::new(move |app| {
+    TestServer::with_factory(move || {
         // Build SQL Identity policy
-        let policy = SqlIdentityBuilder::new(uri.clone()).response_header(RESPONSE_HEADER);
+        let policy = SqlIdentityBuilder::new(uri.clone())
+            .response_header(RESPONSE_HEADER)
+            .refresh_header(REFRESH_HEADER);
+        let policy = customize(policy)
+            .finish()
+            .expect("failed to connect to database");
+        let refresh = policy.refresh_handler();
 
-        app.middleware(IdentityService::new(
-            policy.finish().expect("failed to connect to database"),
-        )).resource("/", |r| r.get().f(|_| HttpResponse::Ok()))
+        App::new()
+            .middleware(IdentityService::new(policy))
+            .resource("/", |r| r.get().f(|_| HttpResponse::Ok()))
             .resource("/login", |r| {
                 r.post().f(|req: &HttpRequest| {
-                    req.remember("mike".to_string());
+                    let user = req.query().get("user").cloned();
+                    req.remember(user.unwrap_or_else(|| "mike".to_string()));
                     HttpResponse::Ok()
                 })
             })
@@ -91,10 +137,67 @@ pub fn build_test_server<S: Into<String>>(uri: S) -> TestServer {
                     req.forget();
                     HttpResponse::Ok()
                 })
-            });
+            })
+            .resource("/logout/all", |r| {
+                r.post().f(|req: &HttpRequest| -> FutureResponse<HttpResponse> {
+                    Box::new(
+                        req.forget_everywhere(false)
+                            .map(|_| HttpResponse::Ok().finish()),
+                    )
+                })
+            })
+            .resource("/logout/others", |r| {
+                r.post().f(|req: &HttpRequest| -> FutureResponse<HttpResponse> {
+                    Box::new(
+                        req.forget_everywhere(true)
+                            .map(|_| HttpResponse::Ok().finish()),
+                    )
+                })
+            })
+            .resource("/devices", |r| {
+                r.get().f(|req: &HttpRequest| -> FutureResponse<HttpResponse> {
+                    Box::new(req.sessions().map(|sessions| {
+                        let ids: Vec<String> = sessions
+                            .iter()
+                            .map(|s| format!("{}{}", s.id, if s.current { "*" } else { "" }))
+                            .collect();
+
+                        HttpResponse::Ok().body(ids.join(","))
+                    }))
+                })
+            })
+            .resource("/devices/{id}", |r| {
+                r.delete().f(|req: &HttpRequest| -> FutureResponse<HttpResponse> {
+                    let id = req.match_info().query("id").unwrap_or(-1);
+                    Box::new(req.revoke_session(id).map(|found| {
+                        if found {
+                            HttpResponse::Ok().finish()
+                        } else {
+                            HttpResponse::NotFound().finish()
+                        }
+                    }))
+                })
+            })
+            .resource("/refresh", |r| r.post().h(refresh))
+            .scope("/sessions", sessions_scope)
     })
 }
 
+/// Builds a new test server using a specific SQL variant, reading the
+/// connection string from an environment variable, and allowing the
+/// identity policy to be customized.  Returns a new TestServer instance
+///
+/// # Arguments
+///
+/// * `variant` - The SQL variant to use (Sqlite, MySQL, or PostgreSQL)
+/// * `customize` - Function to apply extra options to the identity policy
+pub fn build_test_server_from_env_with<F>(variant: SqlVariant, customize: F) -> TestServer
+where
+    F: Fn(SqlIdentityBuilder) -> SqlIdentityBuilder + Clone + Send + 'static,
+{
+    build_test_server_with(env_uri(variant), customize)
+}
+
 /// Adds an authorization bearer token to a request
 ///
 /// # Arguments
@@ -129,8 +232,9 @@ pub fn check_response(srv: &mut TestServer, req: ClientRequest, exp: StatusCode)
 ///
 /// * `srv` - An instance of a TestServer
 /// * `username` - Username to login
-pub fn login(srv: &mut TestServer, _username: &str) -> Option<String> {
-    let request = srv.post().uri(srv.url("/login")).finish().unwrap();
+pub fn login(srv: &mut TestServer, username: &str) -> Option<String> {
+    let uri = srv.url(&format!("/login?user={}", username));
+    let request = srv.post().uri(uri).finish().unwrap();
 
     println!("{:?}", request);
     let response = srv.execute(request.send()).unwrap();
@@ -143,6 +247,29 @@ pub fn login(srv: &mut TestServer, _username: &str) -> Option<String> {
     }
 }
 
+/// Attempts to log a user in on a request that is already authenticated,
+/// returning the new token issued
+///
+/// # Arguments
+///
+/// * `srv` - An instance of a TestServer
+/// * `token` - The token the request is authenticated with
+pub fn login_with_token(srv: &mut TestServer, token: &str) -> Option<String> {
+    let mut request = srv.post();
+    let mut request = request.uri(srv.url("/login"));
+    add_token_to_request(&mut request, token);
+
+    let request = request.finish().unwrap();
+    let response = srv.execute(request.send()).unwrap();
+    println!("{:?}", response);
+    assert!(response.status() == StatusCode::OK, "Login Failed");
+
+    response
+        .headers()
+        .get(RESPONSE_HEADER)
+        .map(|token| token.to_str().unwrap().to_string())
+}
+
 /// Attempts to log the user out with the provided token
 ///
 /// # Arguments
@@ -163,6 +290,25 @@ pub fn logout(srv: &mut TestServer, token: Option<&str>, code: StatusCode) {
     assert!(check_response(srv, request, code));
 }
 
+/// Attempts to log the user out of every session with the provided token
+///
+/// # Arguments
+///
+/// * `srv` - An instance of a TestServer
+/// * `token` - The token corresponding to the user to log out
+/// * `keep_current` - True to keep the session of the token alive
+/// * `code` - Status code to expect (200 Ok, 401 Unauthorized, etc...)
+pub fn logout_everywhere(srv: &mut TestServer, token: &str, keep_current: bool, code: StatusCode) {
+    let path = if keep_current { "/logout/others" } else { "/logout/all" };
+
+    let mut request = srv.post();
+    let mut request = request.uri(srv.url(path));
+    add_token_to_request(&mut request, token);
+
+    let request = request.finish().unwrap();
+    assert!(check_response(srv, request, code));
+}
+
 /// Builds a common get request, appending an auth token if provided.
 /// The url for the route will hit the endpoint specified in uri
 ///
@@ -209,3 +355,348 @@ pub fn profile(srv: &mut TestServer, token: Option<&str>, code: StatusCode) {
     let request = build_get(srv, "/profile", token);
     assert!(check_response(srv, request, code));
 }
+
+/// Attempts to get a page
+///
+/// # Arguments
+///
+/// * `srv` - An instance of a TestServer
+/// * `path` - Path of the page to get
+/// * `token` - An optional authorization token
+/// * `code` - Status code to expect (200 Ok, 401 Unauthorized, etc...)
+pub fn page(srv: &mut TestServer, path: &str, token: Option<&str>, code: StatusCode) {
+    let request = build_get(srv, path, token);
+    assert!(check_response(srv, request, code));
+}
+
+/// Sends a request with a token provided in a cookie, returning the response
+///
+/// # Arguments
+///
+/// * `srv` - An instance of a TestServer
+/// * `method` - HTTP method to use
+/// * `uri` - Endpoint to hit
+/// * `name` - Name of the cookie to place the token in
+/// * `token` - An optional authorization token
+pub fn send_with_cookie(
+    srv: &mut TestServer,
+    method: Method,
+    uri: &str,
+    name: &str,
+    token: Option<&str>,
+) -> ClientResponse {
+    let mut request = srv.client(method, uri);
+
+    if let Some(token) = token {
+        request.cookie(Cookie::new(name.to_string(), token.to_string()));
+    }
+
+    let request = request.finish().unwrap();
+
+    println!("{:?}", request);
+    let response = srv.execute(request.send()).unwrap();
+    println!("{:?}", response);
+
+    response
+}
+
+/// Attempts to log a user in, returning the token set in the given cookie
+/// Note: The server automatically assumes authentication passes
+///
+/// # Arguments
+///
+/// * `srv` - An instance of a TestServer
+/// * `name` - Name of the cookie the token is returned in
+pub fn login_cookie(srv: &mut TestServer, name: &str) -> Option<String> {
+    let response = send_with_cookie(srv, Method::POST, "/login", name, None);
+    assert!(response.status() == StatusCode::OK, "Login Failed");
+
+    response.cookie(name).map(|c| c.value().to_string())
+}
+
+/// Attempts to get the profile page, providing a raw header value instead
+/// of building a bearer authorization header
+///
+/// # Arguments
+///
+/// * `srv` - An instance of a TestServer
+/// * `name` - Name of the header to set
+/// * `value` - Value of the header to set
+/// * `code` - Status code to expect (200 Ok, 401 Unauthorized, etc...)
+pub fn profile_with_header(srv: &mut TestServer, name: &str, value: &str, code: StatusCode) {
+    let request = srv.get()
+        .uri(srv.url("/profile"))
+        .header(name, value)
+        .finish()
+        .unwrap();
+
+    assert!(check_response(srv, request, code));
+}
+
+/// Attempts to get the profile page, providing the token in the query string
+///
+/// # Arguments
+///
+/// * `srv` - An instance of a TestServer
+/// * `name` - Name of the query parameter to set
+/// * `token` - Percent-encoded token to place in the query string
+/// * `code` - Status code to expect (200 Ok, 401 Unauthorized, etc...)
+pub fn profile_with_query(srv: &mut TestServer, name: &str, token: &str, code: StatusCode) {
+    let uri = format!("/profile?{}={}", name, token);
+    let request = build_get(srv, &uri, None);
+    assert!(check_response(srv, request, code));
+}
+
+/// Attempts to get the profile page, returning any new token the server
+/// placed in the response header (e.g., after rotating the token)
+///
+/// # Arguments
+///
+/// * `srv` - An instance of a TestServer
+/// * `token` - An optional authorization token
+/// * `code` - Status code to expect (200 Ok, 401 Unauthorized, etc...)
+pub fn profile_new_token(srv: &mut TestServer, token: Option<&str>, code: StatusCode) -> Option<String> {
+    let request = build_get(srv, "/profile", token);
+    let response = srv.execute(request.send()).unwrap();
+    println!("{:?}", response);
+    assert!(response.status() == code);
+
+    response
+        .headers()
+        .get(RESPONSE_HEADER)
+        .map(|token| token.to_str().unwrap().to_string())
+}
+
+/// Extracts the access and refresh tokens from a response, if both are set
+///
+/// # Arguments
+///
+/// * `response` - Response recieved from the server
+fn token_pair(response: &ClientResponse) -> Option<(String, String)> {
+    let access = response.headers().get(RESPONSE_HEADER)?;
+    let refresh = response.headers().get(REFRESH_HEADER)?;
+
+    Some((
+        access.to_str().unwrap().to_string(),
+        refresh.to_str().unwrap().to_string(),
+    ))
+}
+
+/// Logs a user in, returning the access and refresh tokens issued
+///
+/// # Arguments
+///
+/// * `srv` - An instance of a TestServer
+/// * `username` - Username to login
+pub fn login_pair(srv: &mut TestServer, username: &str) -> Option<(String, String)> {
+    let uri = srv.url(&format!("/login?user={}", username));
+    let request = srv.post().uri(uri).finish().unwrap();
+    let response = srv.execute(request.send()).unwrap();
+    println!("{:?}", response);
+    assert!(response.status() == StatusCode::OK, "Login Failed");
+
+    token_pair(&response)
+}
+
+/// Exchanges a refresh token for a new access and refresh token pair
+///
+/// # Arguments
+///
+/// * `srv` - An instance of a TestServer
+/// * `token` - The refresh token to exchange
+/// * `code` - Status code to expect (200 Ok, 401 Unauthorized, etc...)
+pub fn refresh(srv: &mut TestServer, token: &str, code: StatusCode) -> Option<(String, String)> {
+    let request = refresh_request(srv, token);
+    let response = srv.execute(request.send()).unwrap();
+    println!("{:?}", response);
+    assert!(response.status() == code);
+
+    token_pair(&response)
+}
+
+/// Builds a request exchanging a refresh token for a new pair, without
+/// sending it
+///
+/// # Arguments
+///
+/// * `srv` - An instance of a TestServer
+/// * `token` - The refresh token to exchange
+pub fn refresh_request(srv: &mut TestServer, token: &str) -> ClientRequest {
+    let mut request = srv.post();
+    let mut request = request.uri(srv.url("/refresh"));
+    add_token_to_request(&mut request, token);
+
+    request.finish().unwrap()
+}
+
+/// Exchanges a refresh token, sent in the refresh header, for a new pair
+/// while also authenticating with an access token (as clients that attach
+/// the access token to every request do)
+///
+/// # Arguments
+///
+/// * `srv` - An instance of a TestServer
+/// * `access` - The access token to send in the authorization header
+/// * `token` - The refresh token to exchange
+/// * `code` - Status code to expect (200 Ok, 401 Unauthorized, etc...)
+pub fn refresh_with_access(
+    srv: &mut TestServer,
+    access: &str,
+    token: &str,
+    code: StatusCode,
+) -> Option<(String, String)> {
+    let mut request = srv.post();
+    let mut request = request.uri(srv.url("/refresh"));
+    add_token_to_request(&mut request, access);
+    request.header(REFRESH_HEADER, token);
+
+    let request = request.finish().unwrap();
+    let response = srv.execute(request.send()).unwrap();
+    println!("{:?}", response);
+    assert!(response.status() == code);
+
+    token_pair(&response)
+}
+
+/// Lists the sessions of the user a token belongs to, returning their ids
+/// and the id of the session of the token
+///
+/// # Arguments
+///
+/// * `srv` - An instance of a TestServer
+/// * `token` - The token of the user to list the sessions of
+pub fn devices(srv: &mut TestServer, token: &str) -> (Vec<i64>, Option<i64>) {
+    let request = build_get(srv, "/devices", Some(token));
+    let response = srv.execute(request.send()).unwrap();
+    println!("{:?}", response);
+    assert!(response.status() == StatusCode::OK);
+
+    let body = srv.execute(response.body()).unwrap();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+
+    let mut ids = vec![];
+    let mut current = None;
+    for id in body.split(',').filter(|id| !id.is_empty()) {
+        let value = id.trim_end_matches('*').parse().unwrap();
+        if id.ends_with('*') {
+            current = Some(value);
+        }
+        ids.push(value);
+    }
+
+    (ids, current)
+}
+
+/// Revokes a session of the user a token belongs to
+///
+/// # Arguments
+///
+/// * `srv` - An instance of a TestServer
+/// * `token` - The token of the user the session belongs to
+/// * `id` - Id of the session to revoke
+/// * `code` - Status code to expect (200 Ok, 404 Not Found, etc...)
+pub fn revoke_device(srv: &mut TestServer, token: &str, id: i64, code: StatusCode) {
+    let mut request = ClientRequest::build();
+    request
+        .method(Method::DELETE)
+        .uri(srv.url(&format!("/devices/{}", id)));
+    add_token_to_request(&mut request, token);
+
+    let request = request.finish().unwrap();
+    assert!(check_response(srv, request, code));
+}
+
+/// Sends a request to the session management scope, returning the JSON
+/// body of the response (if any)
+///
+/// # Arguments
+///
+/// * `srv` - An instance of a TestServer
+/// * `method` - HTTP method to use
+/// * `path` - Path within the scope (e.g., `/` or `/1`)
+/// * `token` - An optional authorization token
+/// * `code` - Status code to expect (200 Ok, 401 Unauthorized, etc...)
+pub fn sessions(
+    srv: &mut TestServer,
+    method: Method,
+    path: &str,
+    token: Option<&str>,
+    code: StatusCode,
+) -> Option<Value> {
+    let mut request = ClientRequest::build();
+    request.method(method).uri(srv.url(&format!("/sessions{}", path)));
+
+    if let Some(token) = token {
+        add_token_to_request(&mut request, token);
+    }
+
+    let request = request.finish().unwrap();
+    let response = srv.execute(request.send()).unwrap();
+    println!("{:?}", response);
+    assert!(response.status() == code);
+
+    let body = srv.execute(response.body()).unwrap();
+    serde_json::from_slice(&body).ok()
+}
+
+/// Attempts to get the profile page with a specific user agent
+///
+/// # Arguments
+///
+/// * `srv` - An instance of a TestServer
+/// * `token` - An authorization token
+/// * `ua` - User agent to send
+/// * `code` - Status code to expect (200 Ok, 401 Unauthorized, etc...)
+pub fn profile_with_agent(srv: &mut TestServer, token: &str, ua: &str, code: StatusCode) {
+    let mut request = srv.get();
+    let mut request = request.uri(srv.url("/profile"));
+    request.header("User-Agent", ua);
+    add_token_to_request(&mut request, token);
+
+    let request = request.finish().unwrap();
+    assert!(check_response(srv, request, code));
+}
+
+/// Logs a user in with an extra header (e.g., `X-Forwarded-For`)
+///
+/// # Arguments
+///
+/// * `srv` - An instance of a TestServer
+/// * `username` - Username to login
+/// * `name` - Name of the header
+/// * `value` - Value of the header
+pub fn login_with_header(srv: &mut TestServer, username: &str, name: &str, value: &str) -> Option<String> {
+    let mut request = srv.post();
+    let request = request.uri(srv.url(&format!("/login?user={}", username)));
+    request.header(name, value);
+
+    let request = request.finish().unwrap();
+    let response = srv.execute(request.send()).unwrap();
+    println!("{:?}", response);
+    assert!(response.status() == StatusCode::OK, "Login Failed");
+
+    response
+        .headers()
+        .get(RESPONSE_HEADER)
+        .map(|token| token.to_str().unwrap().to_string())
+}
+
+/// Gets a page, returning the `WWW-Authenticate` header of the response
+///
+/// # Arguments
+///
+/// * `srv` - An instance of a TestServer
+/// * `path` - Path of the page to get
+/// * `token` - The token the request is authenticated with
+/// * `code` - Status code to expect (200 Ok, 401 Unauthorized, etc...)
+pub fn authenticate_header(srv: &mut TestServer, path: &str, token: &str, code: StatusCode) -> Option<String> {
+    let request = build_get(srv, path, Some(token));
+    let response = srv.execute(request.send()).unwrap();
+    println!("{:?}", response);
+    assert_eq!(response.status(), code);
+
+    response
+        .headers()
+        .get("WWW-Authenticate")
+        .map(|value| value.to_str().unwrap().to_string())
+}
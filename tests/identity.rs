@@ -2,12 +2,40 @@
 
 extern crate actix_web;
 extern crate actix_web_sql_identity;
+extern crate chrono;
+extern crate diesel;
 extern crate dotenv;
+extern crate failure;
+extern crate futures;
+extern crate serde_json;
 
 mod common;
 
-use actix_web::http::StatusCode;
+use actix_web::http::{Method, StatusCode};
 use actix_web::test::TestServer;
+use actix_web::{HttpMessage, HttpResponse};
+
+use chrono::Duration;
+
+use failure::Error;
+
+use futures::Future;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::fs;
+use std::sync::Arc;
+use std::thread;
+use std::time;
+
+use actix_web_sql_identity::{
+    Binding, BindingPolicy, Column, EvictionPolicy, FailurePolicy, SameSite, SqlIdentityBuilder,
+    SqlIdentityError, TokenFormat, TokenSource,
+};
+
+use actix_web_sql_identity::store::{
+    CreateSession, Delete, DieselStore, IdentityStore, RefreshIdentity, RotateIdentity,
+    SqlIdentityModel, UpdateIdentity,
+};
 
 use common::SqlVariant;
 
@@ -40,6 +68,12 @@ fn pg_get_index() {
     get_index(srv);
 }
 
+#[test]
+fn memory_get_index() {
+    let srv = common::build_test_server_from_env(SqlVariant::Memory);
+    get_index(srv);
+}
+
 /// Retrieves profile page with no token supplied
 ///
 /// Token: None
@@ -69,6 +103,12 @@ fn pg_no_identity() {
     no_identity(srv);
 }
 
+#[test]
+fn memory_no_identity() {
+    let srv = common::build_test_server_from_env(SqlVariant::Memory);
+    no_identity(srv);
+}
+
 /// Retrives the profile page with an invalid token supplied
 ///
 /// Token: Invalid
@@ -98,6 +138,12 @@ fn pg_invalid_token() {
     invalid_token(srv);
 }
 
+#[test]
+fn memory_invalid_token() {
+    let srv = common::build_test_server_from_env(SqlVariant::Memory);
+    invalid_token(srv);
+}
+
 /// Retrievs the profile page with a valid token
 ///
 /// Token: Valid
@@ -134,6 +180,189 @@ fn pg_valid_token() {
     valid_token(srv);
 }
 
+/// Retrieves the profile page with a token that has expired
+///
+/// Token: Expired
+/// Expected Result: 401 Unauthorized
+fn expired_token(mut srv: TestServer) {
+    common::profile(
+        &mut srv,
+        Some("c4e3ef5e2ff1e8b1c3ee4a3e0b1fa3d1"),
+        StatusCode::UNAUTHORIZED,
+    );
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_expired_token() {
+    let srv = common::build_test_server_from_env(SqlVariant::Sqlite);
+    expired_token(srv);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_expired_token() {
+    let srv = common::build_test_server_from_env(SqlVariant::MySql);
+    expired_token(srv);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_expired_token() {
+    let srv = common::build_test_server_from_env(SqlVariant::Postgres);
+    expired_token(srv);
+}
+
+/// Logs in with a maximum token age set, then waits for the token to expire
+///
+/// Token: Valid, then Expired
+/// Expected Result: 200 OK, then 401 Unauthorized
+fn max_age(mut srv: TestServer) {
+    let token = match common::login(&mut srv, "mike") {
+        Some(t) => t,
+        None => panic!("Token not found! Login Failed"),
+    };
+
+    common::profile(&mut srv, Some(&token), StatusCode::OK);
+
+    thread::sleep(time::Duration::from_secs(2));
+    common::profile(&mut srv, Some(&token), StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_max_age() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Sqlite, |policy| {
+        policy.max_age(Duration::seconds(1))
+    });
+    max_age(srv);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_max_age() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::MySql, |policy| {
+        policy.max_age(Duration::seconds(1))
+    });
+    max_age(srv);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_max_age() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Postgres, |policy| {
+        policy.max_age(Duration::seconds(1))
+    });
+    max_age(srv);
+}
+
+#[test]
+fn memory_max_age() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Memory, |policy| {
+        policy.max_age(Duration::seconds(1))
+    });
+    max_age(srv);
+}
+
+/// Retrieves the profile page with a token that has not been used in a
+/// long time, with and without an idle timeout configured.  The idle
+/// server must be checked first, as any successful use refreshes the token
+///
+/// Token: Idle
+/// Expected Result: 401 Unauthorized with a timeout, 200 OK without a timeout
+fn idle_token(mut srv: TestServer, mut idle_srv: TestServer) {
+    common::profile(
+        &mut idle_srv,
+        Some("0b8a4fd1a37e5ce1f4a84bcc1b6e03a9"),
+        StatusCode::UNAUTHORIZED,
+    );
+
+    common::profile(
+        &mut srv,
+        Some("0b8a4fd1a37e5ce1f4a84bcc1b6e03a9"),
+        StatusCode::OK,
+    );
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_idle_token() {
+    let srv = common::build_test_server_from_env(SqlVariant::Sqlite);
+    let idle_srv = common::build_test_server_from_env_with(SqlVariant::Sqlite, |policy| {
+        policy.idle_timeout(Duration::hours(1))
+    });
+    idle_token(srv, idle_srv);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_idle_token() {
+    let srv = common::build_test_server_from_env(SqlVariant::MySql);
+    let idle_srv = common::build_test_server_from_env_with(SqlVariant::MySql, |policy| {
+        policy.idle_timeout(Duration::hours(1))
+    });
+    idle_token(srv, idle_srv);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_idle_token() {
+    let srv = common::build_test_server_from_env(SqlVariant::Postgres);
+    let idle_srv = common::build_test_server_from_env_with(SqlVariant::Postgres, |policy| {
+        policy.idle_timeout(Duration::hours(1))
+    });
+    idle_token(srv, idle_srv);
+}
+
+/// Retrieves the profile page with a token stored in plaintext (as done
+/// by earlier versions), with and without legacy tokens accepted
+///
+/// Token: Legacy
+/// Expected Result: 401 Unauthorized by default, 200 OK when accepted
+fn legacy_token(mut srv: TestServer, mut legacy_srv: TestServer) {
+    common::profile(
+        &mut srv,
+        Some("5e7b1a9c02d84f36a1e0c9b8d7f6a5e4"),
+        StatusCode::UNAUTHORIZED,
+    );
+
+    common::profile(
+        &mut legacy_srv,
+        Some("5e7b1a9c02d84f36a1e0c9b8d7f6a5e4"),
+        StatusCode::OK,
+    );
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_legacy_token() {
+    let srv = common::build_test_server_from_env(SqlVariant::Sqlite);
+    let legacy_srv = common::build_test_server_from_env_with(SqlVariant::Sqlite, |policy| {
+        policy.legacy_tokens(true)
+    });
+    legacy_token(srv, legacy_srv);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_legacy_token() {
+    let srv = common::build_test_server_from_env(SqlVariant::MySql);
+    let legacy_srv = common::build_test_server_from_env_with(SqlVariant::MySql, |policy| {
+        policy.legacy_tokens(true)
+    });
+    legacy_token(srv, legacy_srv);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_legacy_token() {
+    let srv = common::build_test_server_from_env(SqlVariant::Postgres);
+    let legacy_srv = common::build_test_server_from_env_with(SqlVariant::Postgres, |policy| {
+        policy.legacy_tokens(true)
+    });
+    legacy_token(srv, legacy_srv);
+}
+
 /// Tests all endpoints with all conditions
 fn login_logout(mut srv: TestServer) {
     // Make sure we can get the index (pass ok)
@@ -159,9 +388,9 @@ fn login_logout(mut srv: TestServer) {
     println!("######### PROFILE #3 #########");
     common::profile(&mut srv, Some(&token), StatusCode::OK);
 
-    // Log out (no token, expect fail bad request)
+    // Log out (no token, expect fail unauthorized)
     println!("######### LOGOUT #1 #########");
-    common::logout(&mut srv, None, StatusCode::BAD_REQUEST);
+    common::logout(&mut srv, None, StatusCode::UNAUTHORIZED);
 
     // Log out (with token, expect pass ok)
     println!("######### LOGOUT #2 #########");
@@ -199,6 +428,12 @@ fn pg_login_logout() {
     login_logout(srv);
 }
 
+#[test]
+fn memory_login_logout() {
+    let srv = common::build_test_server_from_env(SqlVariant::Memory);
+    login_logout(srv);
+}
+
 /// Test logging out of a client when a user has multiple clients
 /// connected (aka multiple entries for userid in table)
 fn multiple_logout(mut srv: TestServer) {
@@ -261,3 +496,1612 @@ fn pg_multiple_logout() {
     let srv = common::build_test_server_from_env(SqlVariant::Postgres);
     multiple_logout(srv);
 }
+
+#[test]
+fn memory_multiple_logout() {
+    let srv = common::build_test_server_from_env(SqlVariant::Memory);
+    multiple_logout(srv);
+}
+
+/// Logs in and out using split (selector.verifier) tokens, and makes sure
+/// a token with the right selector but wrong verifier is rejected
+fn split_token(mut srv: TestServer) {
+    let token = match common::login(&mut srv, "mike") {
+        Some(t) => t,
+        None => panic!("Token not found! Login Failed"),
+    };
+
+    let selector = match token.find('.') {
+        Some(idx) => &token[..idx],
+        None => panic!("Token is not a split token!"),
+    };
+
+    let forged = format!("{}.{}", selector, "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA");
+
+    common::profile(&mut srv, Some(&token), StatusCode::OK);
+    common::profile(&mut srv, Some(&forged), StatusCode::UNAUTHORIZED);
+    common::profile(&mut srv, Some(selector), StatusCode::UNAUTHORIZED);
+
+    common::logout(&mut srv, Some(&token), StatusCode::OK);
+    common::profile(&mut srv, Some(&token), StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_split_token() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Sqlite, |policy| {
+        policy.token_format(TokenFormat::Split)
+    });
+    split_token(srv);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_split_token() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::MySql, |policy| {
+        policy.token_format(TokenFormat::Split)
+    });
+    split_token(srv);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_split_token() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Postgres, |policy| {
+        policy.token_format(TokenFormat::Split)
+    });
+    split_token(srv);
+}
+
+#[test]
+fn memory_split_token() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Memory, |policy| {
+        policy.token_format(TokenFormat::Split)
+    });
+    split_token(srv);
+}
+
+/// Logs in and out using a cookie to transport the token
+fn cookie_token(mut srv: TestServer) {
+    let token = match common::login_cookie(&mut srv, "test-cookie") {
+        Some(t) => t,
+        None => panic!("Token cookie not found! Login Failed"),
+    };
+
+    let token = Some(token.as_ref());
+
+    let resp = common::send_with_cookie(&mut srv, Method::GET, "/profile", "test-cookie", token);
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = common::send_with_cookie(&mut srv, Method::POST, "/logout", "test-cookie", token);
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let cleared = resp.cookie("test-cookie").map(|c| c.value().to_string());
+    assert_eq!(cleared, Some(String::new()));
+
+    // Cookies are only sent on same-site requests by default
+    let same_site = resp.cookie("test-cookie").and_then(|c| c.same_site());
+    assert_eq!(same_site, Some(SameSite::Lax));
+
+    let resp = common::send_with_cookie(&mut srv, Method::GET, "/profile", "test-cookie", token);
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_cookie_token() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Sqlite, |policy| {
+        policy.cookie("test-cookie").cookie_secure(false)
+    });
+    cookie_token(srv);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_cookie_token() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::MySql, |policy| {
+        policy.cookie("test-cookie").cookie_secure(false)
+    });
+    cookie_token(srv);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_cookie_token() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Postgres, |policy| {
+        policy.cookie("test-cookie").cookie_secure(false)
+    });
+    cookie_token(srv);
+}
+
+#[test]
+fn memory_cookie_token() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Memory, |policy| {
+        policy.cookie("test-cookie").cookie_secure(false)
+    });
+    cookie_token(srv);
+}
+
+/// Retrieves the profile page with a valid token, but a scheme other
+/// than bearer in the authorization header
+///
+/// Token: Valid (wrong scheme)
+/// Expected Result: 401 Unauthorized
+fn wrong_scheme(mut srv: TestServer) {
+    let token = "g8mlRUwF1AKx7/ZRvReQ+dRhGpoDAzIC";
+
+    let basic = format!("Basic {}", token);
+    let foo = format!("Foo {}", token);
+    let bearer = format!("bearer {}", token);
+
+    common::profile_with_header(&mut srv, "Authorization", &basic, StatusCode::UNAUTHORIZED);
+    common::profile_with_header(&mut srv, "Authorization", &foo, StatusCode::UNAUTHORIZED);
+    common::profile_with_header(&mut srv, "Authorization", &bearer, StatusCode::OK);
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_wrong_scheme() {
+    let srv = common::build_test_server_from_env(SqlVariant::Sqlite);
+    wrong_scheme(srv);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_wrong_scheme() {
+    let srv = common::build_test_server_from_env(SqlVariant::MySql);
+    wrong_scheme(srv);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_wrong_scheme() {
+    let srv = common::build_test_server_from_env(SqlVariant::Postgres);
+    wrong_scheme(srv);
+}
+
+/// Retrieves the profile page with a valid token provided by custom token
+/// sources (a header and a query parameter), after the bearer scheme
+///
+/// Token: Valid
+/// Expected Result: 200 OK from any configured source
+fn token_sources(mut srv: TestServer) {
+    let token = "g8mlRUwF1AKx7/ZRvReQ+dRhGpoDAzIC";
+    let encoded = "g8mlRUwF1AKx7%2FZRvReQ%2BdRhGpoDAzIC";
+
+    common::profile(&mut srv, Some(token), StatusCode::OK);
+    common::profile_with_header(&mut srv, "X-Test-Token", token, StatusCode::OK);
+    common::profile_with_query(&mut srv, "token", encoded, StatusCode::OK);
+    common::profile_with_query(&mut srv, "other", encoded, StatusCode::UNAUTHORIZED);
+}
+
+fn with_token_sources(policy: SqlIdentityBuilder) -> SqlIdentityBuilder {
+    policy.token_sources(vec![
+        TokenSource::Bearer,
+        TokenSource::Header("X-Test-Token".to_string()),
+        TokenSource::Query("token".to_string()),
+    ])
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_token_sources() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Sqlite, with_token_sources);
+    token_sources(srv);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_token_sources() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::MySql, with_token_sources);
+    token_sources(srv);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_token_sources() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Postgres, with_token_sources);
+    token_sources(srv);
+}
+
+/// Logs in, waits for the token to become due for rotation, and makes sure
+/// a new token is issued.  The old token must remain valid during the grace
+/// period (if any), and be rejected afterwards
+///
+/// Token: Valid, then Rotated
+/// Expected Result: 200 OK with new token, old token depends on grace period
+fn rotate_token(mut srv: TestServer, grace: bool) {
+    let token = match common::login(&mut srv, "mike") {
+        Some(t) => t,
+        None => panic!("Token not found! Login Failed"),
+    };
+
+    // Not yet due for rotation, no new token issued
+    let rotated = common::profile_new_token(&mut srv, Some(&token), StatusCode::OK);
+    assert_eq!(rotated, None);
+
+    thread::sleep(time::Duration::from_secs(2));
+
+    let rotated = match common::profile_new_token(&mut srv, Some(&token), StatusCode::OK) {
+        Some(t) => t,
+        None => panic!("Token was not rotated!"),
+    };
+    assert!(rotated != token);
+
+    common::profile(&mut srv, Some(&rotated), StatusCode::OK);
+
+    if grace {
+        common::profile(&mut srv, Some(&token), StatusCode::OK);
+    } else {
+        common::profile(&mut srv, Some(&token), StatusCode::UNAUTHORIZED);
+    }
+}
+
+fn with_rotation(policy: SqlIdentityBuilder) -> SqlIdentityBuilder {
+    policy.rotate_every(Duration::seconds(1))
+}
+
+fn with_rotation_no_grace(policy: SqlIdentityBuilder) -> SqlIdentityBuilder {
+    policy
+        .token_format(TokenFormat::Split)
+        .rotate_every(Duration::seconds(1))
+        .rotation_grace(Duration::seconds(0))
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_rotate_token() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Sqlite, with_rotation);
+    rotate_token(srv, true);
+
+    let srv = common::build_test_server_from_env_with(SqlVariant::Sqlite, with_rotation_no_grace);
+    rotate_token(srv, false);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_rotate_token() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::MySql, with_rotation);
+    rotate_token(srv, true);
+
+    let srv = common::build_test_server_from_env_with(SqlVariant::MySql, with_rotation_no_grace);
+    rotate_token(srv, false);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_rotate_token() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Postgres, with_rotation);
+    rotate_token(srv, true);
+
+    let srv = common::build_test_server_from_env_with(SqlVariant::Postgres, with_rotation_no_grace);
+    rotate_token(srv, false);
+}
+
+#[test]
+fn memory_rotate_token() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Memory, with_rotation);
+    rotate_token(srv, true);
+
+    let srv = common::build_test_server_from_env_with(SqlVariant::Memory, with_rotation_no_grace);
+    rotate_token(srv, false);
+}
+
+/// Logs in again on a request that is already authenticated
+///
+/// Token: Valid token
+/// Expected Result: New token issued, previous token no longer valid
+fn relogin(mut srv: TestServer) {
+    let token = match common::login(&mut srv, "mike") {
+        Some(t) => t,
+        None => panic!("Token not issued!"),
+    };
+
+    let new_token = match common::login_with_token(&mut srv, &token) {
+        Some(t) => t,
+        None => panic!("Token not issued on second login!"),
+    };
+    assert!(new_token != token);
+
+    common::profile(&mut srv, Some(&token), StatusCode::UNAUTHORIZED);
+    common::profile(&mut srv, Some(&new_token), StatusCode::OK);
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_relogin() {
+    let srv = common::build_test_server_from_env(SqlVariant::Sqlite);
+    relogin(srv);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_relogin() {
+    let srv = common::build_test_server_from_env(SqlVariant::MySql);
+    relogin(srv);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_relogin() {
+    let srv = common::build_test_server_from_env(SqlVariant::Postgres);
+    relogin(srv);
+}
+
+#[test]
+fn memory_relogin() {
+    let srv = common::build_test_server_from_env(SqlVariant::Memory);
+    relogin(srv);
+}
+
+/// Logs a user out of every other session, then out of every session
+///
+/// Token: Several valid tokens for the same user
+/// Expected Result: Only the current token survives, then none do
+fn logout_everywhere(mut srv: TestServer) {
+    let tokens: Vec<String> = (0..3)
+        .map(|_| common::login(&mut srv, "everywhere").expect("Token not issued!"))
+        .collect();
+    let other = common::login(&mut srv, "elsewhere").expect("Token not issued!");
+
+    common::logout_everywhere(&mut srv, &tokens[0], true, StatusCode::OK);
+    common::profile(&mut srv, Some(&tokens[0]), StatusCode::OK);
+    common::profile(&mut srv, Some(&tokens[1]), StatusCode::UNAUTHORIZED);
+    common::profile(&mut srv, Some(&tokens[2]), StatusCode::UNAUTHORIZED);
+
+    common::logout_everywhere(&mut srv, &tokens[0], false, StatusCode::OK);
+    common::profile(&mut srv, Some(&tokens[0]), StatusCode::UNAUTHORIZED);
+    common::logout_everywhere(&mut srv, &tokens[0], false, StatusCode::UNAUTHORIZED);
+
+    // Sessions of other users are left alone
+    common::profile(&mut srv, Some(&other), StatusCode::OK);
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_logout_everywhere() {
+    let srv = common::build_test_server_from_env(SqlVariant::Sqlite);
+    logout_everywhere(srv);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_logout_everywhere() {
+    let srv = common::build_test_server_from_env(SqlVariant::MySql);
+    logout_everywhere(srv);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_logout_everywhere() {
+    let srv = common::build_test_server_from_env(SqlVariant::Postgres);
+    logout_everywhere(srv);
+}
+
+#[test]
+fn memory_logout_everywhere() {
+    let srv = common::build_test_server_from_env(SqlVariant::Memory);
+    logout_everywhere(srv);
+}
+
+/// Lists the sessions of a user, then revokes one of them
+///
+/// Token: Two valid tokens for the same user
+/// Expected Result: Both sessions listed, revoked session no longer valid
+fn list_sessions(mut srv: TestServer) {
+    let first = common::login(&mut srv, "devices").expect("Token not issued!");
+    let second = common::login(&mut srv, "devices").expect("Token not issued!");
+
+    let (ids, first_id) = common::devices(&mut srv, &first);
+    let (_, second_id) = common::devices(&mut srv, &second);
+    let (first_id, second_id) = (first_id.unwrap(), second_id.unwrap());
+
+    assert_eq!(ids.len(), 2);
+    assert!(ids.contains(&first_id) && ids.contains(&second_id));
+    assert!(first_id != second_id);
+
+    common::revoke_device(&mut srv, &first, second_id, StatusCode::OK);
+    common::profile(&mut srv, Some(&second), StatusCode::UNAUTHORIZED);
+    common::revoke_device(&mut srv, &first, second_id, StatusCode::NOT_FOUND);
+
+    let (ids, _) = common::devices(&mut srv, &first);
+    assert_eq!(ids, vec![first_id]);
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_list_sessions() {
+    let srv = common::build_test_server_from_env(SqlVariant::Sqlite);
+    list_sessions(srv);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_list_sessions() {
+    let srv = common::build_test_server_from_env(SqlVariant::MySql);
+    list_sessions(srv);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_list_sessions() {
+    let srv = common::build_test_server_from_env(SqlVariant::Postgres);
+    list_sessions(srv);
+}
+
+#[test]
+fn memory_list_sessions() {
+    let srv = common::build_test_server_from_env(SqlVariant::Memory);
+    list_sessions(srv);
+}
+
+/// Manages sessions through the session management scope
+///
+/// Token: Several valid tokens for the same user
+/// Expected Result: Sessions listed as JSON, and revoked individually or
+/// all at once
+fn sessions_scope(mut srv: TestServer) {
+    let first = common::login(&mut srv, "scope").expect("Token not issued!");
+    let second = common::login(&mut srv, "scope").expect("Token not issued!");
+
+    common::sessions(&mut srv, Method::GET, "", None, StatusCode::UNAUTHORIZED);
+
+    let listed = common::sessions(&mut srv, Method::GET, "", Some(&second), StatusCode::OK)
+        .expect("Sessions not listed!");
+    let listed = listed.as_array().unwrap();
+    assert_eq!(listed.len(), 2);
+
+    let current: Vec<_> = listed.iter().filter(|s| s["current"] == true).collect();
+    assert_eq!(current.len(), 1);
+    let second_id = current[0]["id"].as_i64().unwrap();
+
+    let path = format!("/{}", second_id);
+    common::sessions(&mut srv, Method::DELETE, &path, Some(&first), StatusCode::NO_CONTENT);
+    common::sessions(&mut srv, Method::DELETE, &path, Some(&first), StatusCode::NOT_FOUND);
+    common::profile(&mut srv, Some(&second), StatusCode::UNAUTHORIZED);
+
+    let third = common::login(&mut srv, "scope").expect("Token not issued!");
+    common::sessions(&mut srv, Method::DELETE, "/", Some(&first), StatusCode::NO_CONTENT);
+    common::profile(&mut srv, Some(&third), StatusCode::UNAUTHORIZED);
+    common::profile(&mut srv, Some(&first), StatusCode::OK);
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_sessions_scope() {
+    let srv = common::build_test_server_from_env(SqlVariant::Sqlite);
+    sessions_scope(srv);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_sessions_scope() {
+    let srv = common::build_test_server_from_env(SqlVariant::MySql);
+    sessions_scope(srv);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_sessions_scope() {
+    let srv = common::build_test_server_from_env(SqlVariant::Postgres);
+    sessions_scope(srv);
+}
+
+#[test]
+fn memory_sessions_scope() {
+    let srv = common::build_test_server_from_env(SqlVariant::Memory);
+    sessions_scope(srv);
+}
+
+/// Logs a user in more times than the session limit allows
+///
+/// Token: New logins for the same user
+/// Expected Result: Least recently used session evicted, or the login
+/// rejected with 403 Forbidden
+fn max_sessions(mut srv: TestServer, user: &str, policy: EvictionPolicy) {
+    let first = common::login(&mut srv, user).expect("Token not issued!");
+    let second = common::login(&mut srv, user).expect("Token not issued!");
+    common::profile(&mut srv, Some(&second), StatusCode::OK);
+
+    match policy {
+        EvictionPolicy::EvictLeastRecent => {
+            let third = common::login(&mut srv, user).expect("Token not issued!");
+            common::profile(&mut srv, Some(&first), StatusCode::UNAUTHORIZED);
+            common::profile(&mut srv, Some(&second), StatusCode::OK);
+            common::profile(&mut srv, Some(&third), StatusCode::OK);
+        }
+        EvictionPolicy::Reject => {
+            let uri = srv.url(&format!("/login?user={}", user));
+            let request = srv.post().uri(uri).finish().unwrap();
+            assert!(common::check_response(&mut srv, request, StatusCode::FORBIDDEN));
+            common::profile(&mut srv, Some(&first), StatusCode::OK);
+            common::profile(&mut srv, Some(&second), StatusCode::OK);
+        }
+    }
+}
+
+fn with_max_sessions(policy: SqlIdentityBuilder) -> SqlIdentityBuilder {
+    policy.max_sessions_per_user(2)
+}
+
+fn with_max_sessions_reject(policy: SqlIdentityBuilder) -> SqlIdentityBuilder {
+    policy
+        .max_sessions_per_user(2)
+        .eviction_policy(EvictionPolicy::Reject)
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_max_sessions() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Sqlite, with_max_sessions);
+    max_sessions(srv, "evicted", EvictionPolicy::EvictLeastRecent);
+
+    let srv = common::build_test_server_from_env_with(SqlVariant::Sqlite, with_max_sessions_reject);
+    max_sessions(srv, "rejected", EvictionPolicy::Reject);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_max_sessions() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::MySql, with_max_sessions);
+    max_sessions(srv, "evicted", EvictionPolicy::EvictLeastRecent);
+
+    let srv = common::build_test_server_from_env_with(SqlVariant::MySql, with_max_sessions_reject);
+    max_sessions(srv, "rejected", EvictionPolicy::Reject);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_max_sessions() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Postgres, with_max_sessions);
+    max_sessions(srv, "evicted", EvictionPolicy::EvictLeastRecent);
+
+    let srv = common::build_test_server_from_env_with(SqlVariant::Postgres, with_max_sessions_reject);
+    max_sessions(srv, "rejected", EvictionPolicy::Reject);
+}
+
+#[test]
+fn memory_max_sessions() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Memory, with_max_sessions);
+    max_sessions(srv, "evicted", EvictionPolicy::EvictLeastRecent);
+
+    let srv = common::build_test_server_from_env_with(SqlVariant::Memory, with_max_sessions_reject);
+    max_sessions(srv, "rejected", EvictionPolicy::Reject);
+}
+
+/// Lets a session expire, then waits for the background purge.  Runs on a
+/// store of its own, as the purge deletes every expired identity it finds
+///
+/// Token: Token that expires after 1 second, and one that never expires
+/// Expected Result: Purge deletes only the expired session
+fn purge_expired(uri: String) {
+    let purged = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&purged);
+
+    let mut kept = common::build_test_server_with(uri.clone(), |policy| policy.run_migrations(true));
+    let mut srv = common::build_test_server_with(uri, move |policy| {
+        let counter = Arc::clone(&counter);
+
+        policy
+            .run_migrations(true)
+            .max_age(Duration::seconds(1))
+            .purge_every(Duration::seconds(1))
+            .purge_batch_size(1)
+            .on_purge(move |n| {
+                counter.fetch_add(n, Ordering::SeqCst);
+            })
+    });
+
+    let kept_token = common::login(&mut kept, "kept").expect("Token not issued!");
+    let token = common::login(&mut srv, "purged").expect("Token not issued!");
+    thread::sleep(time::Duration::from_secs(3));
+
+    common::profile(&mut srv, Some(&token), StatusCode::UNAUTHORIZED);
+    common::profile(&mut kept, Some(&kept_token), StatusCode::OK);
+    assert_eq!(purged.load(Ordering::SeqCst), 1, "Expired session not purged alone!");
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_purge_expired() {
+    let uri = common::env_uri(SqlVariant::Sqlite).replace(".sqlite3", "-purged.sqlite3");
+    fs::remove_file(&uri).ok();
+
+    purge_expired(uri);
+}
+
+#[test]
+fn memory_purge_expired() {
+    purge_expired(common::env_uri(SqlVariant::Memory));
+}
+
+/// Uses a session bound to its client from a different user agent
+///
+/// Token: Valid token, bound to the client address and user agent
+/// Expected Result: 200 OK from the same client, 401 Unauthorized from a
+/// different user agent (200 OK if mismatches are only logged)
+fn bound_session(mut srv: TestServer, enforced: bool) {
+    let token = common::login(&mut srv, "bound").expect("Token not issued!");
+    common::profile(&mut srv, Some(&token), StatusCode::OK);
+
+    let code = if enforced { StatusCode::UNAUTHORIZED } else { StatusCode::OK };
+    common::profile_with_agent(&mut srv, &token, "Stolen/1.0", code);
+}
+
+fn with_binding(policy: SqlIdentityBuilder) -> SqlIdentityBuilder {
+    policy.bind_sessions(vec![
+        Binding::Ip,
+        Binding::Subnet { v4: 24, v6: 64 },
+        Binding::UserAgent,
+    ])
+}
+
+fn with_binding_log_only(policy: SqlIdentityBuilder) -> SqlIdentityBuilder {
+    with_binding(policy).binding_policy(BindingPolicy::LogOnly)
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_bound_session() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Sqlite, with_binding);
+    bound_session(srv, true);
+
+    let srv = common::build_test_server_from_env_with(SqlVariant::Sqlite, with_binding_log_only);
+    bound_session(srv, false);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_bound_session() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::MySql, with_binding);
+    bound_session(srv, true);
+
+    let srv = common::build_test_server_from_env_with(SqlVariant::MySql, with_binding_log_only);
+    bound_session(srv, false);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_bound_session() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Postgres, with_binding);
+    bound_session(srv, true);
+
+    let srv = common::build_test_server_from_env_with(SqlVariant::Postgres, with_binding_log_only);
+    bound_session(srv, false);
+}
+
+#[test]
+fn memory_bound_session() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Memory, with_binding);
+    bound_session(srv, true);
+
+    let srv = common::build_test_server_from_env_with(SqlVariant::Memory, with_binding_log_only);
+    bound_session(srv, false);
+}
+
+/// Logs in with forwarded headers, then checks the address recorded
+///
+/// Token: New login, with `X-Forwarded-For` or `Forwarded` headers
+/// Expected Result: Forwarded address only recorded when the peer is a
+/// trusted proxy
+fn client_address(mut srv: TestServer, trusted: bool) {
+    let recorded = |srv: &mut TestServer, name: &str, value: &str| {
+        let token = common::login_with_header(srv, "proxied", name, value).expect("Token not issued!");
+        let listed = common::sessions(srv, Method::GET, "", Some(&token), StatusCode::OK)
+            .expect("Sessions not listed!");
+
+        listed
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|s| s["current"] == true)
+            .map(|s| s["ip"].as_str().unwrap().to_string())
+            .unwrap()
+    };
+
+    let forwarded_for = recorded(&mut srv, "X-Forwarded-For", "2001:db8::1, 127.0.0.1");
+    let forwarded = recorded(&mut srv, "Forwarded", "for=\"[2001:db8::2]:4711\";proto=https");
+
+    if trusted {
+        assert_eq!(forwarded_for, "2001:db8::1");
+        assert_eq!(forwarded, "2001:db8::2");
+    } else {
+        assert_eq!(forwarded_for, "127.0.0.1");
+        assert_eq!(forwarded, "127.0.0.1");
+    }
+}
+
+fn with_trusted_proxies(policy: SqlIdentityBuilder) -> SqlIdentityBuilder {
+    policy.trusted_proxies(vec!["127.0.0.0/8", "::1"])
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_client_address() {
+    let srv = common::build_test_server_from_env(SqlVariant::Sqlite);
+    client_address(srv, false);
+
+    let srv = common::build_test_server_from_env_with(SqlVariant::Sqlite, with_trusted_proxies);
+    client_address(srv, true);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_client_address() {
+    let srv = common::build_test_server_from_env(SqlVariant::MySql);
+    client_address(srv, false);
+
+    let srv = common::build_test_server_from_env_with(SqlVariant::MySql, with_trusted_proxies);
+    client_address(srv, true);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_client_address() {
+    let srv = common::build_test_server_from_env(SqlVariant::Postgres);
+    client_address(srv, false);
+
+    let srv = common::build_test_server_from_env_with(SqlVariant::Postgres, with_trusted_proxies);
+    client_address(srv, true);
+}
+
+#[test]
+fn memory_client_address() {
+    let srv = common::build_test_server_from_env(SqlVariant::Memory);
+    client_address(srv, false);
+
+    let srv = common::build_test_server_from_env_with(SqlVariant::Memory, with_trusted_proxies);
+    client_address(srv, true);
+}
+
+/// Exchanges refresh tokens for new pairs, then reuses a refresh token
+///
+/// Token: Access and refresh token pair
+/// Expected Result: Refresh tokens cannot be used as access tokens, and
+/// reusing a refresh token revokes every token issued from the login
+fn refresh_token(mut srv: TestServer) {
+    let (access, refresh) = match common::login_pair(&mut srv, "mike") {
+        Some(pair) => pair,
+        None => panic!("Token pair not issued!"),
+    };
+
+    common::profile(&mut srv, Some(&access), StatusCode::OK);
+    common::profile(&mut srv, Some(&refresh), StatusCode::UNAUTHORIZED);
+
+    let (new_access, new_refresh) = match common::refresh(&mut srv, &refresh, StatusCode::OK) {
+        Some(pair) => pair,
+        None => panic!("Token pair not refreshed!"),
+    };
+
+    common::profile(&mut srv, Some(&access), StatusCode::UNAUTHORIZED);
+    common::profile(&mut srv, Some(&new_access), StatusCode::OK);
+
+    // The refresh header wins over an access token sent alongside it
+    let (new_access, new_refresh) =
+        match common::refresh_with_access(&mut srv, &new_access, &new_refresh, StatusCode::OK) {
+            Some(pair) => pair,
+            None => panic!("Token pair not refreshed with an access token!"),
+        };
+
+    common::profile(&mut srv, Some(&new_access), StatusCode::OK);
+
+    // Reusing the first refresh token revokes the whole family
+    common::refresh(&mut srv, &refresh, StatusCode::UNAUTHORIZED);
+    common::profile(&mut srv, Some(&new_access), StatusCode::UNAUTHORIZED);
+    common::refresh(&mut srv, &new_refresh, StatusCode::UNAUTHORIZED);
+}
+
+fn with_refresh(policy: SqlIdentityBuilder) -> SqlIdentityBuilder {
+    policy.refresh_tokens(Duration::minutes(5), Duration::days(1))
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_refresh_token() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Sqlite, with_refresh);
+    refresh_token(srv);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_refresh_token() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::MySql, with_refresh);
+    refresh_token(srv);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_refresh_token() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Postgres, with_refresh);
+    refresh_token(srv);
+}
+
+#[test]
+fn memory_refresh_token() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Memory, with_refresh);
+    refresh_token(srv);
+}
+
+/// Exchanges the same refresh token twice at once
+///
+/// Token: Refresh token, sent in two concurrent requests
+/// Expected Result: At most one request gets a new pair
+fn concurrent_refresh(mut srv: TestServer) {
+    for _ in 0..5 {
+        let (_, refresh) = common::login_pair(&mut srv, "raced").expect("Token pair not issued!");
+
+        let first = common::refresh_request(&mut srv, &refresh);
+        let second = common::refresh_request(&mut srv, &refresh);
+        let (first, second) = srv.execute(first.send().join(second.send())).unwrap();
+        println!("{:?} {:?}", first, second);
+
+        let refreshed = [first.status(), second.status()]
+            .iter()
+            .filter(|&&status| status == StatusCode::OK)
+            .count();
+        assert!(refreshed <= 1, "Refresh token used twice!");
+    }
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_concurrent_refresh() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Sqlite, with_refresh);
+    concurrent_refresh(srv);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_concurrent_refresh() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::MySql, with_refresh);
+    concurrent_refresh(srv);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_concurrent_refresh() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Postgres, with_refresh);
+    concurrent_refresh(srv);
+}
+
+#[test]
+fn memory_concurrent_refresh() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Memory, with_refresh);
+    concurrent_refresh(srv);
+}
+
+/// Logs a user in again after the access token of their only session
+/// expired, with a limit of one session
+///
+/// Token: Access and refresh token pairs, the first access token expired
+/// Expected Result: The first session (still refreshable) is evicted
+fn refresh_sessions(mut srv: TestServer) {
+    let (_, first) = common::login_pair(&mut srv, "refreshed").expect("Token pair not issued!");
+    thread::sleep(time::Duration::from_secs(2));
+
+    let (access, second) = common::login_pair(&mut srv, "refreshed").expect("Token pair not issued!");
+    common::profile(&mut srv, Some(&access), StatusCode::OK);
+
+    common::refresh(&mut srv, &first, StatusCode::UNAUTHORIZED);
+    common::refresh(&mut srv, &second, StatusCode::OK);
+}
+
+fn with_refresh_sessions(policy: SqlIdentityBuilder) -> SqlIdentityBuilder {
+    policy
+        .refresh_tokens(Duration::seconds(1), Duration::days(1))
+        .max_sessions_per_user(1)
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_refresh_sessions() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Sqlite, with_refresh_sessions);
+    refresh_sessions(srv);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_refresh_sessions() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::MySql, with_refresh_sessions);
+    refresh_sessions(srv);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_refresh_sessions() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Postgres, with_refresh_sessions);
+    refresh_sessions(srv);
+}
+
+#[test]
+fn memory_refresh_sessions() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Memory, with_refresh_sessions);
+    refresh_sessions(srv);
+}
+
+/// Uses an expired token with an error handler that challenges expired
+/// tokens, and an unknown token (left to the default handling)
+///
+/// Token: Expired, then unknown
+/// Expected Result: 401 Unauthorized with a `WWW-Authenticate` header for
+/// the expired token (even on public pages), anonymous for the unknown one,
+/// 404 Not Found for refreshing it (refresh tokens are not enabled)
+fn error_handler(mut srv: TestServer) {
+    let expired = "c4e3ef5e2ff1e8b1c3ee4a3e0b1fa3d1";
+    let challenge = Some("Bearer error=\"invalid_token\"".to_string());
+
+    assert_eq!(common::authenticate_header(&mut srv, "/profile", expired, StatusCode::UNAUTHORIZED), challenge);
+    assert_eq!(common::authenticate_header(&mut srv, "/", expired, StatusCode::UNAUTHORIZED), challenge);
+
+    let unknown = "b2d8c1f0e4a7b6c5d3e2f1a0b9c8d7e6";
+    assert_eq!(common::authenticate_header(&mut srv, "/", unknown, StatusCode::OK), None);
+    assert_eq!(common::authenticate_header(&mut srv, "/profile", unknown, StatusCode::UNAUTHORIZED), None);
+
+    // Logging out without a token is challenged as well
+    let request = srv.post().uri(srv.url("/logout")).finish().unwrap();
+    let response = srv.execute(request.send()).unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers().get("WWW-Authenticate").map(|v| v.to_str().unwrap()), Some("Bearer"));
+
+    // So is managing sessions without a token
+    let request = srv.get().uri(srv.url("/sessions")).finish().unwrap();
+    let response = srv.execute(request.send()).unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers().get("WWW-Authenticate").map(|v| v.to_str().unwrap()), Some("Bearer"));
+
+    // Refresh tokens are not enabled, left to the default response
+    common::refresh(&mut srv, unknown, StatusCode::NOT_FOUND);
+}
+
+fn with_error_handler(policy: SqlIdentityBuilder) -> SqlIdentityBuilder {
+    policy.error_handler(|e| match *e {
+        SqlIdentityError::TokenExpired => Some(
+            HttpResponse::Unauthorized()
+                .header("WWW-Authenticate", "Bearer error=\"invalid_token\"")
+                .finish(),
+        ),
+        SqlIdentityError::TokenRequired => Some(
+            HttpResponse::Unauthorized()
+                .header("WWW-Authenticate", "Bearer")
+                .finish(),
+        ),
+        _ => None,
+    })
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_error_handler() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Sqlite, with_error_handler);
+    error_handler(srv);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_error_handler() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::MySql, with_error_handler);
+    error_handler(srv);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_error_handler() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Postgres, with_error_handler);
+    error_handler(srv);
+}
+
+/// Uses a token against a database without an identities table, so every
+/// lookup fails
+///
+/// Token: Valid format, but the database is unavailable
+/// Expected Result: Anonymous when failing open, 503 Service Unavailable
+/// otherwise (the token was never cached), anonymous without a token
+fn backend_unavailable(policy: FailurePolicy) {
+    let uri = common::env_uri(SqlVariant::Sqlite).replace(".sqlite3", "-missing.sqlite3");
+    fs::remove_file(&uri).ok();
+
+    let mut srv = common::build_test_server_with(uri, move |p| p.failure_policy(policy));
+    let token = "5e7b1a9c02d84f36a1e0c9b8d7f6a5e4";
+
+    let code = match policy {
+        FailurePolicy::FailOpen => StatusCode::OK,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    common::index(&mut srv, None);
+    common::page(&mut srv, "/", Some(token), code);
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_backend_unavailable() {
+    backend_unavailable(FailurePolicy::FailOpen);
+    backend_unavailable(FailurePolicy::FailClosed);
+    backend_unavailable(FailurePolicy::ServeCached(Duration::minutes(5)));
+}
+
+/// Uses a token cached by one server after it was forgotten through another
+///
+/// Token: Valid, cached
+/// Expected Result: The caching server keeps accepting the token until it is
+/// forgotten through that server (or goes stale)
+fn cached_token(variant: SqlVariant) {
+    let uri = common::env_uri(variant);
+    let mut cached = common::build_test_server_with(uri.clone(), |p| {
+        p.cache_tokens(100, Duration::minutes(5))
+    });
+    let mut other = common::build_test_server(uri);
+
+    let token = common::login(&mut cached, "cached").expect("Token not issued!");
+    common::profile(&mut cached, Some(&token), StatusCode::OK);
+
+    common::logout(&mut other, Some(&token), StatusCode::OK);
+    common::profile(&mut other, Some(&token), StatusCode::UNAUTHORIZED);
+    common::profile(&mut cached, Some(&token), StatusCode::OK);
+
+    common::logout(&mut cached, Some(&token), StatusCode::OK);
+    common::profile(&mut cached, Some(&token), StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_cached_token() {
+    cached_token(SqlVariant::Sqlite);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_cached_token() {
+    cached_token(SqlVariant::MySql);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_cached_token() {
+    cached_token(SqlVariant::Postgres);
+}
+
+#[test]
+fn memory_cached_token() {
+    cached_token(SqlVariant::Memory);
+}
+
+/// Keeps using a cached token for longer than the idle timeout
+///
+/// Token: Valid, cached, used more often than the idle timeout
+/// Expected Result: 200 OK every time, as each use is seen by the cached
+/// copy, then 401 Unauthorized once it is left idle
+fn cached_idle(variant: SqlVariant) {
+    let mut srv = common::build_test_server_from_env_with(variant, |p| {
+        p.cache_tokens(100, Duration::minutes(5))
+            .idle_timeout(Duration::seconds(4))
+    });
+
+    let token = common::login(&mut srv, "cached-idle").expect("Token not issued!");
+    common::profile(&mut srv, Some(&token), StatusCode::OK);
+
+    for _ in 0..2 {
+        thread::sleep(time::Duration::from_millis(2500));
+        common::profile(&mut srv, Some(&token), StatusCode::OK);
+    }
+
+    thread::sleep(time::Duration::from_secs(5));
+    common::profile(&mut srv, Some(&token), StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_cached_idle() {
+    cached_idle(SqlVariant::Sqlite);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_cached_idle() {
+    cached_idle(SqlVariant::MySql);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_cached_idle() {
+    cached_idle(SqlVariant::Postgres);
+}
+
+#[test]
+fn memory_cached_idle() {
+    cached_idle(SqlVariant::Memory);
+}
+
+/// Uses a rotated token during its grace period, with the token cache on
+///
+/// Token: Rotated, used during and after the grace period
+/// Expected Result: 200 OK during the grace period, 401 Unauthorized after
+/// it (the previous token was not cached)
+fn cached_rotated(variant: SqlVariant) {
+    let mut srv = common::build_test_server_from_env_with(variant, |p| {
+        p.cache_tokens(100, Duration::minutes(5))
+            .rotate_every(Duration::seconds(1))
+            .rotation_grace(Duration::seconds(2))
+    });
+
+    let token = common::login(&mut srv, "cached-rotated").expect("Token not issued!");
+    thread::sleep(time::Duration::from_millis(1500));
+
+    let rotated = common::profile_new_token(&mut srv, Some(&token), StatusCode::OK);
+    assert!(rotated.is_some(), "Token was not rotated!");
+    common::profile(&mut srv, Some(&token), StatusCode::OK);
+
+    thread::sleep(time::Duration::from_secs(3));
+    common::profile(&mut srv, Some(&token), StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_cached_rotated() {
+    cached_rotated(SqlVariant::Sqlite);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_cached_rotated() {
+    cached_rotated(SqlVariant::MySql);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_cached_rotated() {
+    cached_rotated(SqlVariant::Postgres);
+}
+
+#[test]
+fn memory_cached_rotated() {
+    cached_rotated(SqlVariant::Memory);
+}
+
+/// Logs in past the session limit, with the token cache on
+///
+/// Token: Cached, then evicted by a newer login
+/// Expected Result: 401 Unauthorized for the evicted token, even though it
+/// was cached
+fn cached_evicted(variant: SqlVariant) {
+    let mut srv = common::build_test_server_from_env_with(variant, |p| {
+        p.cache_tokens(100, Duration::minutes(5))
+            .max_sessions_per_user(1)
+    });
+
+    let first = common::login(&mut srv, "cached-evicted").expect("Token not issued!");
+    common::profile(&mut srv, Some(&first), StatusCode::OK);
+
+    let second = common::login(&mut srv, "cached-evicted").expect("Token not issued!");
+    common::profile(&mut srv, Some(&second), StatusCode::OK);
+    common::profile(&mut srv, Some(&first), StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_cached_evicted() {
+    cached_evicted(SqlVariant::Sqlite);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_cached_evicted() {
+    cached_evicted(SqlVariant::MySql);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_cached_evicted() {
+    cached_evicted(SqlVariant::Postgres);
+}
+
+#[test]
+fn memory_cached_evicted() {
+    cached_evicted(SqlVariant::Memory);
+}
+
+/// Uses a token several times with a touch interval set
+///
+/// Token: Valid
+/// Expected Result: The last used time is only saved when the client changes
+fn touch_interval(mut srv: TestServer) {
+    let token = common::login(&mut srv, "touched").expect("Token not issued!");
+
+    let modified = |srv: &mut TestServer| {
+        let listed = common::sessions(srv, Method::GET, "", Some(&token), StatusCode::OK)
+            .expect("Sessions not listed!");
+        listed[0]["modified"].clone()
+    };
+
+    let first = modified(&mut srv);
+    thread::sleep(time::Duration::from_millis(1100));
+
+    common::profile(&mut srv, Some(&token), StatusCode::OK);
+    assert_eq!(modified(&mut srv), first);
+
+    common::profile_with_agent(&mut srv, &token, "touch-test", StatusCode::OK);
+    assert!(modified(&mut srv) != first);
+}
+
+fn with_touch_interval(policy: SqlIdentityBuilder) -> SqlIdentityBuilder {
+    policy.touch_interval(Duration::hours(1))
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_touch_interval() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Sqlite, with_touch_interval);
+    touch_interval(srv);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_touch_interval() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::MySql, with_touch_interval);
+    touch_interval(srv);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_touch_interval() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Postgres, with_touch_interval);
+    touch_interval(srv);
+}
+
+#[test]
+fn memory_touch_interval() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Memory, with_touch_interval);
+    touch_interval(srv);
+}
+
+/// Identity store that counts the lookups made through it, to check that
+/// custom stores are used
+struct CountingStore {
+    inner: DieselStore,
+    finds: Arc<AtomicUsize>,
+}
+
+impl IdentityStore for CountingStore {
+    fn find(&self, tokens: &[String], kind: &str) -> Result<Option<SqlIdentityModel>, Error> {
+        self.finds.fetch_add(1, Ordering::SeqCst);
+        self.inner.find(tokens, kind)
+    }
+
+    fn create(&self, session: &CreateSession) -> Result<Vec<i64>, Error> {
+        self.inner.create(session)
+    }
+
+    fn touch(&self, update: &UpdateIdentity) -> Result<usize, Error> {
+        self.inner.touch(update)
+    }
+
+    fn rotate(&self, rotate: &RotateIdentity) -> Result<usize, Error> {
+        self.inner.rotate(rotate)
+    }
+
+    fn refresh(&self, refresh: &RefreshIdentity) -> Result<Option<String>, Error> {
+        self.inner.refresh(refresh)
+    }
+
+    fn delete(&self, target: &Delete) -> Result<usize, Error> {
+        self.inner.delete(target)
+    }
+
+    fn list(&self, userid: &str) -> Result<Vec<SqlIdentityModel>, Error> {
+        self.inner.list(userid)
+    }
+}
+
+/// Logs in and out through a custom identity store
+///
+/// Token: Valid, then logged out
+/// Expected Result: Every lookup goes through the custom store
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_custom_store() {
+    let uri = common::env_uri(SqlVariant::Sqlite);
+    let finds = Arc::new(AtomicUsize::new(0));
+
+    let counted = Arc::clone(&finds);
+    let mut srv = common::build_test_server_with("unused://", move |p| {
+        p.store(CountingStore {
+            inner: DieselStore::sqlite(&uri).expect("failed to open database"),
+            finds: Arc::clone(&counted),
+        })
+    });
+
+    let token = common::login(&mut srv, "stored").expect("Token not issued!");
+    common::profile(&mut srv, Some(&token), StatusCode::OK);
+    common::logout(&mut srv, Some(&token), StatusCode::OK);
+    common::profile(&mut srv, Some(&token), StatusCode::UNAUTHORIZED);
+
+    assert_eq!(finds.load(Ordering::SeqCst), 3);
+}
+
+/// Builds a server that creates its own table in a new database
+///
+/// Token: Valid, then logged out
+/// Expected Result: Valid table created at the latest version, login works
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_run_migrations() {
+    let uri = common::env_uri(SqlVariant::Sqlite).replace(".sqlite3", "-migrated.sqlite3");
+    fs::remove_file(&uri).ok();
+
+    let srv = common::build_test_server_with(uri.clone(), |p| {
+        p.run_migrations(true).validate_schema(true)
+    });
+    login_logout(srv);
+
+    let store = DieselStore::sqlite(&uri).expect("failed to open database");
+    assert_eq!(store.migrate().expect("failed to migrate"), 5);
+
+    assert!(sqlite_has_index(&uri, "identities_previous"));
+    assert!(sqlite_has_index(&uri, "identities_family"));
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_run_migrations() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::MySql, |p| p.run_migrations(true));
+    login_logout(srv);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_run_migrations() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Postgres, |p| p.run_migrations(true));
+    login_logout(srv);
+}
+
+/// Returns true if an index exists in a SQLite database
+///
+/// # Arguments
+///
+/// * `uri` - Path to the database
+/// * `name` - Name of the index
+#[cfg(feature = "sqlite")]
+fn sqlite_has_index(uri: &str, name: &str) -> bool {
+    use diesel::connection::Connection;
+    use diesel::dsl::sql;
+    use diesel::sql_types::Bool;
+    use diesel::sqlite::SqliteConnection;
+    use diesel::RunQueryDsl;
+
+    let conn = SqliteConnection::establish(uri).expect("failed to open database");
+    let query = format!(
+        "EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'index' AND name = '{}')",
+        name
+    );
+
+    diesel::select(sql::<Bool>(&query))
+        .get_result(&conn)
+        .expect("failed to list indexes")
+}
+
+/// Upgrades a table created (without migrations) by an old version
+///
+/// Token: Valid, after the upgrade
+/// Expected Result: Missing columns added, login works
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_upgrade_migrations() {
+    use diesel::connection::{Connection, SimpleConnection};
+    use diesel::sqlite::SqliteConnection;
+
+    let uri = common::env_uri(SqlVariant::Sqlite).replace(".sqlite3", "-upgraded.sqlite3");
+    fs::remove_file(&uri).ok();
+
+    let conn = SqliteConnection::establish(&uri).expect("failed to open database");
+    conn.batch_execute(
+        "CREATE TABLE identities (
+            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            token TEXT UNIQUE NOT NULL,
+            userid TEXT NOT NULL,
+            ip TEXT,
+            useragent TEXT,
+            created DATETIME NOT NULL,
+            modified DATETIME NOT NULL
+        );",
+    ).expect("failed to create table");
+
+    let store = DieselStore::sqlite(&uri).expect("failed to open database");
+    assert_eq!(store.migrate().expect("failed to migrate"), 5);
+    assert_eq!(store.migrate().expect("failed to migrate"), 5);
+
+    let srv = common::build_test_server(uri);
+    login_logout(srv);
+}
+
+/// Migrates a table whose newer columns were added without recording
+/// their versions (e.g., by a server that failed before recording them)
+///
+/// Expected Result: The missing versions are recorded, without adding the
+/// columns again
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_unrecorded_migrations() {
+    use diesel::connection::{Connection, SimpleConnection};
+    use diesel::sqlite::SqliteConnection;
+
+    let uri = common::env_uri(SqlVariant::Sqlite).replace(".sqlite3", "-unrecorded.sqlite3");
+    fs::remove_file(&uri).ok();
+
+    let conn = SqliteConnection::establish(&uri).expect("failed to open database");
+    conn.batch_execute(include_str!("../sql/sqlite.sql")).expect("failed to create table");
+    conn.batch_execute(
+        "CREATE TABLE identities_migrations (
+            version INTEGER PRIMARY KEY NOT NULL,
+            applied DATETIME NOT NULL
+        );
+        INSERT INTO identities_migrations (version, applied) VALUES
+            (1, CURRENT_TIMESTAMP), (2, CURRENT_TIMESTAMP), (3, CURRENT_TIMESTAMP);",
+    ).expect("failed to record versions");
+
+    let store = DieselStore::sqlite(&uri).expect("failed to open database");
+    assert_eq!(store.migrate().expect("failed to migrate"), 5);
+    assert_eq!(store.migrate().expect("failed to migrate"), 5);
+
+    let srv = common::build_test_server(uri);
+    login_logout(srv);
+}
+
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_validate_schema() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Sqlite, |p| p.validate_schema(true));
+    login_logout(srv);
+}
+
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_validate_schema() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::MySql, |p| p.validate_schema(true));
+    login_logout(srv);
+}
+
+#[test]
+#[cfg(feature = "postgres")]
+fn pg_validate_schema() {
+    let srv = common::build_test_server_from_env_with(SqlVariant::Postgres, |p| p.validate_schema(true));
+    login_logout(srv);
+}
+
+/// Builds a policy against a missing table, then a table with missing
+/// columns and mismatched types
+///
+/// Expected Result: `finish()` fails, listing every problem
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_invalid_schema() {
+    use diesel::connection::{Connection, SimpleConnection};
+    use diesel::sqlite::SqliteConnection;
+
+    let uri = common::env_uri(SqlVariant::Sqlite).replace(".sqlite3", "-invalid.sqlite3");
+    fs::remove_file(&uri).ok();
+
+    let problems = |uri: &str| match SqlIdentityBuilder::new(uri).validate_schema(true).finish() {
+        Ok(_) => panic!("invalid schema accepted"),
+        Err(e) => match e.downcast::<SqlIdentityError>() {
+            Ok(SqlIdentityError::InvalidSchema(problems)) => problems,
+            Ok(e) => panic!("unexpected error: {}", e),
+            Err(e) => panic!("unexpected error: {}", e),
+        },
+    };
+
+    let conn = SqliteConnection::establish(&uri).expect("failed to open database");
+    assert_eq!(problems(&uri), "table identities does not exist");
+
+    conn.batch_execute(
+        "CREATE TABLE identities (
+            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            token TEXT UNIQUE NOT NULL,
+            userid TEXT,
+            ip TEXT,
+            useragent TEXT,
+            created INTEGER NOT NULL,
+            modified DATETIME NOT NULL
+        );",
+    ).expect("failed to create table");
+
+    let problems = problems(&uri);
+    assert!(problems.contains("column userid must be NOT NULL"));
+    assert!(problems.contains("column created has type INTEGER"));
+    assert!(problems.contains("column expires is missing"));
+    assert!(problems.contains("column revoked is missing"));
+}
+
+/// Builds a policy against a table whose token column is too short to
+/// hold a token hash
+///
+/// Expected Result: `finish()` fails, naming the short column
+#[test]
+#[cfg(feature = "mysql")]
+fn mysql_short_columns() {
+    use diesel::connection::{Connection, SimpleConnection};
+    use diesel::mysql::MysqlConnection;
+
+    let uri = common::env_uri(SqlVariant::MySql);
+    let conn = MysqlConnection::establish(&uri).expect("failed to open database");
+    conn.batch_execute(
+        "DROP TABLE IF EXISTS short_identities;
+        CREATE TABLE short_identities (
+            id BIGINT PRIMARY KEY AUTO_INCREMENT NOT NULL,
+            token CHAR(32) UNIQUE NOT NULL,
+            userid TEXT NOT NULL,
+            ip TEXT,
+            useragent TEXT,
+            created DATETIME NOT NULL,
+            modified DATETIME NOT NULL,
+            expires DATETIME,
+            verifier TEXT,
+            previous TEXT,
+            rotated DATETIME,
+            kind VARCHAR(16) NOT NULL,
+            family TEXT,
+            revoked DATETIME
+        );",
+    ).expect("failed to create table");
+
+    let built = SqlIdentityBuilder::new(uri)
+        .table_name("short_identities")
+        .validate_schema(true)
+        .finish();
+
+    conn.batch_execute("DROP TABLE short_identities;").expect("failed to drop table");
+
+    match built.map(|_| ()).map_err(|e| e.downcast::<SqlIdentityError>()) {
+        Err(Ok(SqlIdentityError::InvalidSchema(problems))) => {
+            assert_eq!(problems, "column token holds 32 characters, expected at least 44");
+        }
+        Ok(_) => panic!("short token column accepted"),
+        Err(Ok(e)) => panic!("unexpected error: {}", e),
+        Err(Err(e)) => panic!("unexpected error: {}", e),
+    }
+}
+
+/// Stores identities in a table and columns with custom names
+fn renamed(policy: SqlIdentityBuilder) -> SqlIdentityBuilder {
+    policy
+        .table_name("auth_tokens")
+        .column_name(Column::Token, "token_hash")
+        .column_name(Column::UserId, "user_name")
+        .column_name(Column::Family, "login_family")
+        .run_migrations(true)
+        .validate_schema(true)
+}
+
+/// Logs in and out, refreshes tokens and limits sessions with a table
+/// created (by migrations) with custom names
+///
+/// Token: Valid, then logged out
+/// Expected Result: Every query uses the custom names, and the default
+/// table is never created
+#[test]
+#[cfg(feature = "sqlite")]
+fn sqlite_renamed_table() {
+    let uri = common::env_uri(SqlVariant::Sqlite).replace(".sqlite3", "-renamed.sqlite3");
+    fs::remove_file(&uri).ok();
+
+    login_logout(common::build_test_server_with(uri.clone(), renamed));
+    logout_everywhere(common::build_test_server_with(uri.clone(), renamed));
+
+    let srv = common::build_test_server_with(uri.clone(), |p| with_max_sessions(renamed(p)));
+    max_sessions(srv, "evicted", EvictionPolicy::EvictLeastRecent);
+
+    let srv = common::build_test_server_with(uri.clone(), |p| with_refresh(renamed(p)));
+    refresh_token(srv);
+
+    assert!(sqlite_has_index(&uri, "auth_tokens_previous"));
+    assert!(sqlite_has_index(&uri, "auth_tokens_family"));
+
+    match SqlIdentityBuilder::new(uri.as_str()).validate_schema(true).finish() {
+        Ok(_) => panic!("default table created"),
+        Err(e) => assert_eq!(e.to_string(), "invalid identity schema: table identities does not exist"),
+    }
+
+    match SqlIdentityBuilder::new(uri.as_str()).table_name("auth tokens").finish() {
+        Ok(_) => panic!("invalid table name accepted"),
+        Err(e) => assert_eq!(e.to_string(), "invalid table or column name: auth tokens"),
+    }
+}
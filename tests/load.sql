@@ -1,4 +1,6 @@
-INSERT INTO identities (token, userid, ip, created, modified) VALUES ('g8mlRUwF1AKx7/ZRvReQ+dRhGpoDAzIC', 'admin', '129.21.2.103', CURRENT_TIMESTAMP, CURRENT_TIMESTAMP);
-INSERT INTO identities (token, userid, ip, created, modified) VALUES ('605a96d1d49005d40cedc087c0f82cfa', 'george', '33.120.239.23', CURRENT_TIMESTAMP, CURRENT_TIMESTAMP);
-INSERT INTO identities (token, userid, ip, created, modified) VALUES ('95b62b54b511bfa10967d6acbfd5dfe7', 'george', '94.12.185.81', CURRENT_TIMESTAMP, CURRENT_TIMESTAMP);
-
+INSERT INTO identities (token, userid, ip, created, modified) VALUES ('vUpLMC88p2LVxIKoAWPbYcoCO65/YgBKuldUrajeB/g=', 'admin', '129.21.2.103', CURRENT_TIMESTAMP, CURRENT_TIMESTAMP);
+INSERT INTO identities (token, userid, ip, created, modified) VALUES ('JP3gu0oD1Eyco6FwnMXysUvsq+j6+G9tlj2AuFbZhSM=', 'george', '33.120.239.23', CURRENT_TIMESTAMP, CURRENT_TIMESTAMP);
+INSERT INTO identities (token, userid, ip, created, modified) VALUES ('Sc4yFyts+mRLphzFqIyPHiouPRCAuTt/MbEAvMKGZk8=', 'george', '94.12.185.81', CURRENT_TIMESTAMP, CURRENT_TIMESTAMP);
+INSERT INTO identities (token, userid, ip, created, modified, expires) VALUES ('bA6uGhVuR3B24OXEBZHFhTaoKtaXiBC0rBvaZZkQkSg=', 'admin', '129.21.2.103', '2000-01-01 00:00:00', '2000-01-01 00:00:00', '2000-01-02 00:00:00');
+INSERT INTO identities (token, userid, ip, created, modified) VALUES ('DkChQEnSf9DF5fH1Id6SYUExuCERg9i0HSpP3TVqLF4=', 'admin', '129.21.2.103', '2000-01-01 00:00:00', '2000-01-01 00:00:00');
+INSERT INTO identities (token, userid, ip, created, modified) VALUES ('5e7b1a9c02d84f36a1e0c9b8d7f6a5e4', 'admin', '129.21.2.103', CURRENT_TIMESTAMP, CURRENT_TIMESTAMP);